@@ -1,23 +1,38 @@
 use crate::component::Metadata;
 
-#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+const INLINE_BITS: usize = 128;
+const SPILL_BITS: usize = 64;
+
+/// A set of component ids. Ids below 128 live in an inline bitfield, larger ids spill into a
+/// heap-allocated bitfield that is only allocated once such an id is set.
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Default)]
 pub struct Archetype {
     bitfield: u128,
+    // Never has trailing zero words so that equal sets compare and hash equal
+    spill: Vec<u64>,
 }
 
 impl Archetype {
     #[inline(always)]
     pub(crate) fn new() -> Self {
-        Archetype { bitfield: 0 }
+        Archetype {
+            bitfield: 0,
+            spill: Vec::new(),
+        }
     }
 
     #[inline(always)]
     pub(crate) fn set_id(&mut self, id: usize) {
-        if id > 127 {
-            todo!()
+        if id < INLINE_BITS {
+            self.bitfield |= 1 << id;
+            return;
         }
 
-        self.bitfield |= 1 << id;
+        let (word, bit) = Self::spill_position(id);
+        if word >= self.spill.len() {
+            self.spill.resize(word + 1, 0);
+        }
+        self.spill[word] |= 1 << bit;
     }
 
     #[inline(always)]
@@ -27,11 +42,18 @@ impl Archetype {
 
     #[inline(always)]
     pub(crate) fn unset_id(&mut self, id: usize) {
-        if id > 127 {
-            todo!()
+        if id < INLINE_BITS {
+            self.bitfield &= !(1 << id);
+            return;
         }
 
-        self.bitfield &= !(1 << id);
+        let (word, bit) = Self::spill_position(id);
+        if let Some(w) = self.spill.get_mut(word) {
+            *w &= !(1 << bit);
+        }
+        while self.spill.last() == Some(&0) {
+            self.spill.pop();
+        }
     }
 
     #[inline(always)]
@@ -41,7 +63,12 @@ impl Archetype {
 
     #[inline(always)]
     pub(crate) fn contains_id(&self, id: usize) -> bool {
-        self.bitfield & (1 << id) > 0
+        if id < INLINE_BITS {
+            return self.bitfield & (1 << id) > 0;
+        }
+
+        let (word, bit) = Self::spill_position(id);
+        self.spill.get(word).is_some_and(|w| w & (1 << bit) > 0)
     }
 
     #[inline(always)]
@@ -49,7 +76,69 @@ impl Archetype {
         self.contains_id(*metadata.id() as usize)
     }
 
-    pub fn subset_of(&self, other: Archetype) -> bool {
+    pub fn subset_of(&self, other: &Archetype) -> bool {
         (self.bitfield & other.bitfield) == self.bitfield
+            && self.spill.len() <= other.spill.len()
+            && self
+                .spill
+                .iter()
+                .zip(other.spill.iter())
+                .all(|(a, b)| (a & b) == *a)
+    }
+
+    /// Iterates over the ids in the set in ascending order
+    pub fn ids(&self) -> impl Iterator<Item = usize> + '_ {
+        let inline = (0..INLINE_BITS).filter(|id| self.bitfield & (1 << id) > 0);
+        let spill = self.spill.iter().enumerate().flat_map(|(word, w)| {
+            (0..SPILL_BITS)
+                .filter(move |bit| w & (1 << bit) > 0)
+                .map(move |bit| INLINE_BITS + word * SPILL_BITS + bit)
+        });
+        inline.chain(spill)
+    }
+
+    #[inline(always)]
+    fn spill_position(id: usize) -> (usize, usize) {
+        let id = id - INLINE_BITS;
+        (id / SPILL_BITS, id % SPILL_BITS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Archetype;
+
+    #[test]
+    fn set_unset_large_ids() {
+        let mut a = Archetype::new();
+        a.set_id(3);
+        a.set_id(127);
+        a.set_id(128);
+        a.set_id(1000);
+
+        assert!(a.contains_id(3));
+        assert!(a.contains_id(127));
+        assert!(a.contains_id(128));
+        assert!(a.contains_id(1000));
+        assert!(!a.contains_id(999));
+        assert!(!a.contains_id(100000));
+        assert_eq!(a.ids().collect::<Vec<_>>(), vec![3, 127, 128, 1000]);
+
+        let mut b = a.clone();
+        b.unset_id(1000);
+        assert!(b.subset_of(&a));
+        assert!(!a.subset_of(&b));
+
+        b.set_id(1000);
+        assert_eq!(a, b);
+
+        a.unset_id(1000);
+        a.unset_id(128);
+        assert_eq!(a.ids().collect::<Vec<_>>(), vec![3, 127]);
+
+        let mut c = Archetype::new();
+        c.set_id(3);
+        c.set_id(127);
+        assert_eq!(a, c);
     }
 }
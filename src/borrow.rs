@@ -0,0 +1,370 @@
+use std::{
+    any::TypeId,
+    error::Error,
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::Mutex,
+};
+
+use crate::{
+    component::{self, ComponentId},
+    resource::{self, ResourceId},
+    World,
+};
+
+/// The set of components and resources a system reads and writes
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct Access {
+    reads: Vec<ComponentId>,
+    writes: Vec<ComponentId>,
+    tick_reads: Vec<ComponentId>,
+    resource_reads: Vec<ResourceId>,
+    resource_writes: Vec<ResourceId>,
+    side_effects: Vec<TypeId>,
+}
+
+impl Access {
+    pub fn add_read(&mut self, id: ComponentId) {
+        self.reads.push(id);
+    }
+
+    /// Declares a read of the component's change ticks only, e.g. by a `Changed<T>` filter. It
+    /// counts as a read unless the same access also writes the component.
+    pub fn add_tick_read(&mut self, id: ComponentId) {
+        self.tick_reads.push(id);
+    }
+
+    pub fn add_write(&mut self, id: ComponentId) {
+        self.writes.push(id);
+    }
+
+    pub fn add_resource_read(&mut self, id: ResourceId) {
+        self.resource_reads.push(id);
+    }
+
+    pub fn add_resource_write(&mut self, id: ResourceId) {
+        self.resource_writes.push(id);
+    }
+
+    /// Declares an effect other than a borrow, e.g. queuing commands, identified by a type. Nothing
+    /// is borrowed for it, but systems with the same side effect never run in parallel and keep the
+    /// order they were added to their [`Schedule`](crate::Schedule) in.
+    pub fn add_side_effect(&mut self, id: TypeId) {
+        self.side_effects.push(id);
+    }
+
+    pub fn has_side_effect(&self, id: TypeId) -> bool {
+        self.side_effects.contains(&id)
+    }
+
+    pub fn reads(&self) -> &[ComponentId] {
+        &self.reads
+    }
+
+    pub fn writes(&self) -> &[ComponentId] {
+        &self.writes
+    }
+
+    pub fn resource_reads(&self) -> &[ResourceId] {
+        &self.resource_reads
+    }
+
+    pub fn resource_writes(&self) -> &[ResourceId] {
+        &self.resource_writes
+    }
+
+    /// Everything read, including the components only the change ticks of are read
+    fn all_reads(&self) -> impl Iterator<Item = BorrowKey> + '_ {
+        let tick_reads = self.tick_reads.iter();
+        self.reads
+            .iter()
+            .chain(tick_reads.filter(|id| !self.writes.contains(id)))
+            .map(|id| BorrowKey::Component(*id))
+            .chain(
+                self.resource_reads
+                    .iter()
+                    .map(|id| BorrowKey::Resource(*id)),
+            )
+    }
+
+    fn all_writes(&self) -> impl Iterator<Item = BorrowKey> + '_ {
+        let resource_writes = self.resource_writes.iter();
+        self.writes
+            .iter()
+            .map(|id| BorrowKey::Component(*id))
+            .chain(resource_writes.map(|id| BorrowKey::Resource(*id)))
+    }
+
+    /// Returns true if both sets can be borrowed at the same time
+    pub fn is_compatible(&self, other: &Access) -> bool {
+        !self.all_writes().any(|key| {
+            other.all_reads().any(|read| read == key) || other.all_writes().any(|w| w == key)
+        }) && !other
+            .all_writes()
+            .any(|key| self.all_reads().any(|read| read == key))
+    }
+
+    /// Returns true if systems with both sets can run at the same time in any order
+    pub fn is_parallel_with(&self, other: &Access) -> bool {
+        self.is_compatible(other)
+            && !self
+                .side_effects
+                .iter()
+                .any(|id| other.side_effects.contains(id))
+    }
+}
+
+/// What a borrow is of. Components and resources have separate id spaces.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum BorrowKey {
+    Component(ComponentId),
+    Resource(ResourceId),
+}
+
+impl From<ComponentId> for BorrowKey {
+    fn from(id: ComponentId) -> Self {
+        BorrowKey::Component(id)
+    }
+}
+
+impl From<ResourceId> for BorrowKey {
+    fn from(id: ResourceId) -> Self {
+        BorrowKey::Resource(id)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BorrowError {
+    /// The component is borrowed and cannot be borrowed mutably
+    AlreadyBorrowed(ComponentId),
+    /// The component is borrowed mutably and cannot be borrowed at all
+    AlreadyMutablyBorrowed(ComponentId),
+    /// The resource is borrowed and cannot be borrowed mutably
+    ResourceAlreadyBorrowed(ResourceId),
+    /// The resource is borrowed mutably and cannot be borrowed at all
+    ResourceAlreadyMutablyBorrowed(ResourceId),
+}
+
+impl BorrowError {
+    fn already_borrowed(key: BorrowKey) -> Self {
+        match key {
+            BorrowKey::Component(id) => BorrowError::AlreadyBorrowed(id),
+            BorrowKey::Resource(id) => BorrowError::ResourceAlreadyBorrowed(id),
+        }
+    }
+
+    fn already_mutably_borrowed(key: BorrowKey) -> Self {
+        match key {
+            BorrowKey::Component(id) => BorrowError::AlreadyMutablyBorrowed(id),
+            BorrowKey::Resource(id) => BorrowError::ResourceAlreadyMutablyBorrowed(id),
+        }
+    }
+}
+
+impl fmt::Display for BorrowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (id, what) = match self {
+            BorrowError::AlreadyBorrowed(id) => (id, "already borrowed"),
+            BorrowError::AlreadyMutablyBorrowed(id) => (id, "already mutably borrowed"),
+            BorrowError::ResourceAlreadyBorrowed(id) => {
+                return write!(f, "resource {} is already borrowed", resource::name_of(*id))
+            }
+            BorrowError::ResourceAlreadyMutablyBorrowed(id) => {
+                return write!(
+                    f,
+                    "resource {} is already mutably borrowed",
+                    resource::name_of(*id)
+                )
+            }
+        };
+        match component::metadata_of(*id) {
+            Some(metadata) => write!(f, "component {} is {}", metadata.name(), what),
+            None => write!(f, "component {} is {}", id.0, what),
+        }
+    }
+}
+
+impl Error for BorrowError {}
+
+#[derive(Default)]
+struct BorrowState {
+    // Indexed by component id: the number of readers, or -1 if borrowed mutably
+    counts: Vec<isize>,
+    // Same for resources
+    resource_counts: Vec<isize>,
+    outstanding: usize,
+}
+
+impl BorrowState {
+    fn count(&self, key: BorrowKey) -> isize {
+        let (counts, id) = match key {
+            BorrowKey::Component(id) => (&self.counts, id.0),
+            BorrowKey::Resource(id) => (&self.resource_counts, id.0),
+        };
+        counts.get(id as usize).copied().unwrap_or(0)
+    }
+
+    fn count_mut(&mut self, key: BorrowKey) -> &mut isize {
+        let (counts, id) = match key {
+            BorrowKey::Component(id) => (&mut self.counts, id.0 as usize),
+            BorrowKey::Resource(id) => (&mut self.resource_counts, id.0 as usize),
+        };
+        if id >= counts.len() {
+            counts.resize(id + 1, 0);
+        }
+        &mut counts[id]
+    }
+}
+
+/// Tracks which components and resources are borrowed by running systems and outstanding
+/// `Ref`/`RefMut` guards
+#[derive(Default)]
+pub(crate) struct Borrows {
+    state: Mutex<BorrowState>,
+}
+
+impl Borrows {
+    /// Borrows everything in `access`, or nothing if anything is unavailable
+    pub fn acquire(&self, access: &Access) -> Result<(), BorrowError> {
+        let mut state = self.state.lock().unwrap();
+
+        let writes: Vec<_> = access.all_writes().collect();
+        for (i, key) in writes.iter().enumerate() {
+            match state.count(*key) {
+                0 if writes[..i].contains(key) || access.all_reads().any(|read| read == *key) => {
+                    return Err(BorrowError::already_borrowed(*key))
+                }
+                0 => {}
+                n if n > 0 => return Err(BorrowError::already_borrowed(*key)),
+                _ => return Err(BorrowError::already_mutably_borrowed(*key)),
+            }
+        }
+        for key in access.all_reads() {
+            if state.count(key) < 0 {
+                return Err(BorrowError::already_mutably_borrowed(key));
+            }
+        }
+
+        for key in writes {
+            *state.count_mut(key) = -1;
+        }
+        for key in access.all_reads() {
+            *state.count_mut(key) += 1;
+        }
+        state.outstanding += 1;
+
+        Ok(())
+    }
+
+    pub fn release(&self, access: &Access) {
+        let mut state = self.state.lock().unwrap();
+        for key in access.all_writes() {
+            *state.count_mut(key) = 0;
+        }
+        for key in access.all_reads() {
+            *state.count_mut(key) -= 1;
+        }
+        state.outstanding -= 1;
+    }
+
+    pub fn acquire_read(&self, key: BorrowKey) -> Result<(), BorrowError> {
+        let mut state = self.state.lock().unwrap();
+        if state.count(key) < 0 {
+            return Err(BorrowError::already_mutably_borrowed(key));
+        }
+        *state.count_mut(key) += 1;
+        state.outstanding += 1;
+        Ok(())
+    }
+
+    pub fn release_read(&self, key: BorrowKey) {
+        let mut state = self.state.lock().unwrap();
+        *state.count_mut(key) -= 1;
+        state.outstanding -= 1;
+    }
+
+    pub fn acquire_write(&self, key: BorrowKey) -> Result<(), BorrowError> {
+        let mut state = self.state.lock().unwrap();
+        match state.count(key) {
+            0 => {}
+            n if n > 0 => return Err(BorrowError::already_borrowed(key)),
+            _ => return Err(BorrowError::already_mutably_borrowed(key)),
+        }
+        *state.count_mut(key) = -1;
+        state.outstanding += 1;
+        Ok(())
+    }
+
+    pub fn release_write(&self, key: BorrowKey) {
+        let mut state = self.state.lock().unwrap();
+        *state.count_mut(key) = 0;
+        state.outstanding -= 1;
+    }
+
+    /// Returns true while any system or guard holds a borrow
+    pub fn is_borrowed(&self) -> bool {
+        self.state.lock().unwrap().outstanding > 0
+    }
+}
+
+/// A shared borrow of a component or resource returned by [`World::component`] or
+/// [`World::resource`]
+pub struct Ref<'w, T> {
+    value: &'w T,
+    world: &'w World,
+    key: BorrowKey,
+}
+
+impl<'w, T> Ref<'w, T> {
+    pub(crate) fn new(value: &'w T, world: &'w World, key: BorrowKey) -> Self {
+        Ref { value, world, key }
+    }
+}
+
+impl<T> Deref for Ref<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<T> Drop for Ref<'_, T> {
+    fn drop(&mut self) {
+        self.world.release_read(self.key);
+    }
+}
+
+/// A mutable borrow of a component or resource returned by [`World::component_mut`] or
+/// [`World::resource_mut`]
+pub struct RefMut<'w, T> {
+    value: &'w mut T,
+    world: &'w World,
+    key: BorrowKey,
+}
+
+impl<'w, T> RefMut<'w, T> {
+    pub(crate) fn new(value: &'w mut T, world: &'w World, key: BorrowKey) -> Self {
+        RefMut { value, world, key }
+    }
+}
+
+impl<T> Deref for RefMut<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<T> DerefMut for RefMut<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.value
+    }
+}
+
+impl<T> Drop for RefMut<'_, T> {
+    fn drop(&mut self) {
+        self.world.release_write(self.key);
+    }
+}
@@ -0,0 +1,52 @@
+/// When a component was added to its entity and last borrowed mutably, in world ticks. Ticks
+/// wrap around, so they are only compared relative to the tick of the current run.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub(crate) struct ComponentTicks {
+    pub added: u32,
+    pub changed: u32,
+}
+
+impl ComponentTicks {
+    pub fn new(tick: u32) -> Self {
+        ComponentTicks {
+            added: tick,
+            changed: tick,
+        }
+    }
+}
+
+/// The ticks a system compares component ticks against: `this_run` is stamped on the components
+/// it borrows mutably, and whatever was stamped after `last_run` counts as added or changed.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct SystemTicks {
+    pub last_run: u32,
+    pub this_run: u32,
+}
+
+impl SystemTicks {
+    /// Whether `tick` came after the last run. Correct as long as no tick is more than `u32::MAX`
+    /// runs old.
+    #[inline(always)]
+    pub fn is_newer(&self, tick: u32) -> bool {
+        self.this_run.wrapping_sub(tick) < self.this_run.wrapping_sub(self.last_run)
+    }
+}
+
+/// The last run of a system or query used outside of a [`Schedule`](crate::Schedule), which
+/// [`Added`](crate::Added) and [`Changed`](crate::Changed) compare against. Keep one per system
+/// and pass it to every run of it:
+/// ```ignore
+///   let mut last_sync = LastRun::new();
+///   loop {
+///       world.run_with(&mut last_sync, |t: &Transform, _: Changed<Transform>| upload(t));
+///   }
+/// ```
+/// A new one has never run, so everything counts as added and changed.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct LastRun(pub(crate) u32);
+
+impl LastRun {
+    pub fn new() -> Self {
+        LastRun(0)
+    }
+}
@@ -1,46 +1,72 @@
-extern crate lazy_static;
-use lazy_static::lazy_static;
-
 use proc_macro::TokenStream;
 use quote::{quote, ToTokens};
-use std::sync::atomic::{AtomicU32, Ordering};
-
-lazy_static! {
-    static ref NEXT_COMPONENT_ID: AtomicU32 = AtomicU32::new(1); // 0 is reserved
-}
+use syn::parse::Parser;
 
+/// Implements `Component` for a struct or enum. Takes an optional `storage = "table" | "sparse"`
+/// argument, tables being the default.
 #[proc_macro_attribute]
-pub fn component(_attr: TokenStream, item: TokenStream) -> TokenStream {
-    let (ident, generics, id) = if let Ok(item) = syn::parse::<syn::ItemStruct>(item.clone()) {
-        (
-            item.ident,
-            item.generics,
-            NEXT_COMPONENT_ID.fetch_add(1, Ordering::Relaxed),
-        )
+pub fn component(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut storage = quote! { ecs::component::StorageType::Table };
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("storage") {
+            let value: syn::LitStr = meta.value()?.parse()?;
+            storage = match value.value().as_str() {
+                "table" => quote! { ecs::component::StorageType::Table },
+                "sparse" => quote! { ecs::component::StorageType::Sparse },
+                _ => return Err(meta.error("expected \"table\" or \"sparse\"")),
+            };
+            Ok(())
+        } else {
+            Err(meta.error("unsupported component argument"))
+        }
+    });
+    if let Err(err) = parser.parse(attr) {
+        return err.to_compile_error().into();
+    }
+
+    let (ident, mut generics) = if let Ok(item) = syn::parse::<syn::ItemStruct>(item.clone()) {
+        (item.ident, item.generics)
     } else if let Ok(item) = syn::parse::<syn::ItemEnum>(item.clone()) {
-        (
-            item.ident,
-            item.generics,
-            NEXT_COMPONENT_ID.fetch_add(1, Ordering::Relaxed),
-        )
+        (item.ident, item.generics)
     } else {
         panic!("Cannot use this macro here")
     };
 
     let ident_str = format!("{}", ident);
+
+    // Ids are handed out by the runtime registry. A generic type gets one id per instantiation so
+    // it cannot cache its metadata in a single static.
+    let metadata = if generics.params.is_empty() {
+        quote! {
+            static METADATA: std::sync::OnceLock<ecs::component::Metadata> = std::sync::OnceLock::new();
+            *METADATA.get_or_init(|| ecs::component::register_with_storage::<Self>(#ident_str, #storage))
+        }
+    } else {
+        quote! {
+            ecs::component::register_with_storage::<Self>(#ident_str, #storage)
+        }
+    };
+
+    generics
+        .make_where_clause()
+        .predicates
+        .push(syn::parse_quote!(Self: 'static));
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
     let mut out = item.clone();
     out.extend(TokenStream::from(
         quote! {
-            impl #generics ecs::component::Component for #ident #generics {
-                fn metadata(&self) -> ecs::component::Metadata{
-                    ecs::component::Metadata::new(ecs::component::ComponentId(#id), std::mem::size_of::<Self>(), std::mem::align_of::<Self>(),#ident_str)
+            impl #impl_generics ecs::component::Component for #ident #ty_generics #where_clause {
+                fn metadata(&self) -> ecs::component::Metadata {
+                    Self::metadata_static()
                 }
 
                 fn metadata_static() -> ecs::component::Metadata {
-                    ecs::component::Metadata::new(ecs::component::ComponentId(#id), std::mem::size_of::<Self>(), std::mem::align_of::<Self>(),#ident_str)
+                    #metadata
                 }
             }
-        }.into_token_stream()
+        }
+        .into_token_stream(),
     ));
 
     out
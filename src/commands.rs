@@ -0,0 +1,178 @@
+use std::any::TypeId;
+
+use crate::{
+    archetype::Archetype,
+    borrow::Access,
+    change::SystemTicks,
+    component::{Component, Metadata},
+    table::Table,
+    Bundle, Entity, ParallelParam, QueryParam, World,
+};
+
+pub(crate) enum Cmd {
+    AddComponent((Entity, Metadata, Box<dyn Component>)),
+    RemoveComponent((Entity, Metadata)),
+    Despawn(Entity),
+    Insert((Entity, Vec<Box<dyn Component>>)),
+    AddBundle((Entity, Vec<Box<dyn Component>>)),
+    RemoveBundle((Entity, Vec<Metadata>)),
+}
+
+/// Queues structural changes to be applied at the next flush point: when the outermost
+/// [`World::run`] returns, when the last `Ref`/`RefMut` is dropped, or on [`World::flush`].
+/// Commands are applied in the order they were queued.
+///
+/// Can be used from anywhere, including inside systems where it is also available as a parameter:
+/// ```ignore
+///   world.run(|e: &Entity, health: &Health, mut commands: Commands| {
+///       if health.0 <= 0 {
+///           commands.despawn(*e);
+///       }
+///   });
+/// ```
+pub struct Commands<'w> {
+    world: &'w World,
+    // Where the commands go instead of the world's queue, see `Commands::buffered`
+    buffer: Option<Vec<Cmd>>,
+}
+
+impl<'w> Commands<'w> {
+    pub(crate) fn new(world: &'w World) -> Self {
+        Commands {
+            world,
+            buffer: None,
+        }
+    }
+
+    fn push(&mut self, cmd: Cmd) {
+        match self.buffer.as_mut() {
+            Some(buffer) => buffer.push(cmd),
+            None => self.world.push_cmd(cmd),
+        }
+    }
+
+    /// Reserves an entity id right away, the entity itself is created at the next flush point
+    pub fn spawn<B: Bundle>(&mut self, bundle: B) -> Entity {
+        let entity = self.world.reserve_entity();
+        self.push(Cmd::Insert((entity, bundle.into_boxes())));
+        entity
+    }
+
+    pub fn despawn(&mut self, entity: Entity) {
+        self.push(Cmd::Despawn(entity));
+    }
+
+    /// Replaces whatever `entity` holds with `bundle`, see [`World::insert`]
+    pub fn insert<B: Bundle>(&mut self, entity: Entity, bundle: B) {
+        self.push(Cmd::Insert((entity, bundle.into_boxes())));
+    }
+
+    pub fn add_component<T: Component + 'static>(&mut self, entity: Entity, component: T) {
+        self.push(Cmd::AddComponent((
+            entity,
+            T::metadata_static(),
+            Box::new(component),
+        )));
+    }
+
+    pub fn remove_component<T: Component + 'static>(&mut self, entity: Entity) {
+        self.push(Cmd::RemoveComponent((entity, T::metadata_static())));
+    }
+
+    pub fn add_bundle<B: Bundle>(&mut self, entity: Entity, bundle: B) {
+        self.push(Cmd::AddBundle((entity, bundle.into_boxes())));
+    }
+
+    pub fn remove_bundle<B: Bundle>(&mut self, entity: Entity) {
+        self.push(Cmd::RemoveBundle((entity, B::bundle_metadata())));
+    }
+}
+
+/// The commands of a batch of [`Query::par_for_each`](crate::Query::par_for_each), the same as
+/// [`Commands`] except that the components must be `Send`. They are collected per batch and
+/// queued on the world by the calling thread once all the batches are done.
+pub struct ParCommands<'w> {
+    commands: Commands<'w>,
+}
+
+/// Commands whose components are all `Send`, see `ParCommands::into_buffer`
+pub(crate) struct SendCmds(pub Vec<Cmd>);
+
+// `ParCommands` only takes `Send` components, and the other commands hold none
+unsafe impl Send for SendCmds {}
+
+impl<'w> ParCommands<'w> {
+    pub(crate) fn new(world: &'w World) -> Self {
+        ParCommands {
+            commands: Commands {
+                world,
+                buffer: Some(Vec::new()),
+            },
+        }
+    }
+
+    pub(crate) fn into_buffer(self) -> SendCmds {
+        SendCmds(self.commands.buffer.unwrap_or_default())
+    }
+
+    /// See [`Commands::spawn`]
+    pub fn spawn<B: Bundle + Send>(&mut self, bundle: B) -> Entity {
+        self.commands.spawn(bundle)
+    }
+
+    pub fn despawn(&mut self, entity: Entity) {
+        self.commands.despawn(entity);
+    }
+
+    /// See [`Commands::insert`]
+    pub fn insert<B: Bundle + Send>(&mut self, entity: Entity, bundle: B) {
+        self.commands.insert(entity, bundle);
+    }
+
+    pub fn add_component<T: Component + Send + 'static>(&mut self, entity: Entity, component: T) {
+        self.commands.add_component(entity, component);
+    }
+
+    pub fn remove_component<T: Component + 'static>(&mut self, entity: Entity) {
+        self.commands.remove_component::<T>(entity);
+    }
+
+    pub fn add_bundle<B: Bundle + Send>(&mut self, entity: Entity, bundle: B) {
+        self.commands.add_bundle(entity, bundle);
+    }
+
+    pub fn remove_bundle<B: Bundle>(&mut self, entity: Entity) {
+        self.commands.remove_bundle::<B>(entity);
+    }
+}
+
+// A schedule runs the systems that queue commands on the thread calling `Schedule::run`, so the
+// components they queue stay on that thread
+unsafe impl ParallelParam for Commands<'_> {}
+
+impl<'a> QueryParam<'a, Entity, Commands<'a>> for Commands<'a> {
+    type Key = Commands<'static>;
+    type State = &'a World;
+
+    fn state(world: &'a World, _: &'a Table, _: SystemTicks) -> &'a World {
+        world
+    }
+
+    fn run_state(world: &'a World, _: SystemTicks) -> Option<&'a World> {
+        Some(world)
+    }
+
+    #[inline(always)]
+    fn access(world: &&'a World, _: Entity, _: usize) -> Commands<'a> {
+        Commands::new(world)
+    }
+
+    fn match_archetype(_: &Archetype) -> bool {
+        true
+    }
+
+    // Queuing is a side effect on the queue, so that systems queuing commands keep their order
+    fn component_access(access: &mut Access) {
+        access.add_side_effect(TypeId::of::<Cmd>());
+    }
+}
@@ -1,4 +1,13 @@
-use std::ops::Deref;
+use std::{
+    any::TypeId,
+    collections::HashMap,
+    hash::{Hash, Hasher},
+    mem,
+    ops::Deref,
+    sync::{OnceLock, RwLock},
+};
+
+use crate::Entity;
 
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
 pub struct ComponentId(pub u32);
@@ -18,25 +27,132 @@ pub trait Component {
         Self: Sized;
 }
 
+/// Hands out `ComponentId`s to component types the first time they are used. Ids are assigned at
+/// runtime so they do not depend on macro expansion order, and types from different crates never
+/// collide. Id 0 is reserved for `Entity`.
+struct Registry {
+    ids: HashMap<TypeId, ComponentId>,
+    metadata: Vec<Metadata>,
+}
+
+fn registry() -> &'static RwLock<Registry> {
+    static REGISTRY: OnceLock<RwLock<Registry>> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        RwLock::new(Registry {
+            ids: HashMap::from([(TypeId::of::<Entity>(), ComponentId(0))]),
+            metadata: vec![Entity::metadata_static()],
+        })
+    })
+}
+
+/// Returns the metadata of `T`, assigning it the next free id if this is the first time `T` is seen
+pub fn register<T: 'static>(name: &'static str) -> Metadata {
+    register_with_storage::<T>(name, StorageType::Table)
+}
+
+/// Same as [`register`] for a component that is stored as configured by `storage`
+pub fn register_with_storage<T: 'static>(name: &'static str, storage: StorageType) -> Metadata {
+    {
+        let registry = registry().read().unwrap();
+        if let Some(id) = registry.ids.get(&TypeId::of::<T>()) {
+            return registry.metadata[id.0 as usize];
+        }
+    }
+
+    let mut registry = registry().write().unwrap();
+    // Another thread might have registered it between the two locks
+    if let Some(id) = registry.ids.get(&TypeId::of::<T>()) {
+        return registry.metadata[id.0 as usize];
+    }
+
+    let metadata =
+        Metadata::of::<T>(ComponentId(registry.metadata.len() as u32), name).with_storage(storage);
+    registry.ids.insert(TypeId::of::<T>(), metadata.id());
+    registry.metadata.push(metadata);
+    metadata
+}
+
+/// Returns the id assigned to the type, if it has been registered
+pub fn id_of(type_id: TypeId) -> Option<ComponentId> {
+    registry().read().unwrap().ids.get(&type_id).copied()
+}
+
+/// Maps an id back to the metadata of the type it was assigned to
+pub fn metadata_of(id: ComponentId) -> Option<Metadata> {
+    registry()
+        .read()
+        .unwrap()
+        .metadata
+        .get(id.0 as usize)
+        .copied()
+}
+
+/// Drops the `T` that `ptr` points to in place
+///
+/// # Safety
+///
+/// `ptr` must point to a valid, initialized `T` that is not used again afterwards
+pub unsafe fn drop_ptr<T>(ptr: *mut u8) {
+    ptr.cast::<T>().drop_in_place();
+}
+
+/// Where the values of a component live
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum StorageType {
+    /// In a column of the table of the entity's archetype. Fastest to iterate, but adding or
+    /// removing the component moves the entity to another table.
+    #[default]
+    Table,
+    /// In a sparse set indexed by entity, outside of the entity's archetype. Adding or removing
+    /// the component is cheap, which suits components that are toggled often.
+    Sparse,
+}
+
 #[allow(dead_code)]
-#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy)]
 pub struct Metadata {
     id: ComponentId,
     size: usize,
     align: usize,
     name: &'static str,
+    drop: Option<unsafe fn(*mut u8)>,
+    storage: StorageType,
 }
 
 impl Metadata {
+    /// Metadata for a component without drop glue
     pub fn new(id: ComponentId, size: usize, align: usize, name: &'static str) -> Self {
         Metadata {
             id,
             size,
             align,
             name,
+            drop: None,
+            storage: StorageType::Table,
         }
     }
 
+    /// Metadata for `T`, including its drop glue if it needs any
+    pub fn of<T>(id: ComponentId, name: &'static str) -> Self {
+        Metadata {
+            id,
+            size: mem::size_of::<T>(),
+            align: mem::align_of::<T>(),
+            name,
+            drop: if mem::needs_drop::<T>() {
+                Some(drop_ptr::<T>)
+            } else {
+                None
+            },
+            storage: StorageType::Table,
+        }
+    }
+
+    pub fn with_storage(mut self, storage: StorageType) -> Self {
+        self.storage = storage;
+        self
+    }
+
     pub fn id(&self) -> ComponentId {
         self.id
     }
@@ -52,4 +168,37 @@ impl Metadata {
     pub fn name(&self) -> &'static str {
         self.name
     }
+
+    pub fn drop_fn(&self) -> Option<unsafe fn(*mut u8)> {
+        self.drop
+    }
+
+    pub fn storage(&self) -> StorageType {
+        self.storage
+    }
+
+    pub fn is_sparse(&self) -> bool {
+        self.storage == StorageType::Sparse
+    }
+}
+
+// Drop glue and storage are determined by the type, so they are left out of comparisons
+impl PartialEq for Metadata {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+            && self.size == other.size
+            && self.align == other.align
+            && self.name == other.name
+    }
+}
+
+impl Eq for Metadata {}
+
+impl Hash for Metadata {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+        self.size.hash(state);
+        self.align.hash(state);
+        self.name.hash(state);
+    }
 }
@@ -0,0 +1,170 @@
+use std::{alloc::Layout, collections::TryReserveError, error::Error, fmt};
+
+use crate::{
+    borrow::BorrowError,
+    component::{self, ComponentId},
+    Entity,
+};
+
+/// Returned by the `try_` methods of [`World`](crate::World) when storage could not grow
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AllocError {
+    /// The requested capacity does not fit in a valid layout
+    CapacityOverflow,
+    /// The allocator could not provide a column buffer
+    OutOfMemory(Layout),
+    /// Growing one of the world's bookkeeping collections failed
+    Collection(TryReserveError),
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AllocError::CapacityOverflow => write!(f, "capacity overflow"),
+            AllocError::OutOfMemory(layout) => write!(
+                f,
+                "failed to allocate {} bytes aligned to {}",
+                layout.size(),
+                layout.align()
+            ),
+            AllocError::Collection(err) => err.fmt(f),
+        }
+    }
+}
+
+impl Error for AllocError {}
+
+impl From<TryReserveError> for AllocError {
+    fn from(err: TryReserveError) -> Self {
+        AllocError::Collection(err)
+    }
+}
+
+/// Returned by the mutation methods of [`World`](crate::World). Failures of deferred commands
+/// are reported to the handler set with
+/// [`World::on_command_error`](crate::World::on_command_error) when they are applied.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EcsError {
+    /// The entity was never spawned
+    NoSuchEntity(Entity),
+    /// The entity was despawned, its slot might have been reused since
+    StaleEntity(Entity),
+    AlreadyHasComponent(Entity, ComponentId),
+    MissingComponent(Entity, ComponentId),
+    /// Storage for the entity or component could not be allocated
+    CapacityExceeded(AllocError),
+    /// The operation cannot be deferred and would move storage out from under a running system or
+    /// an outstanding borrow
+    Deferred,
+}
+
+fn component_name(id: ComponentId) -> String {
+    match component::metadata_of(id) {
+        Some(metadata) => metadata.name().to_string(),
+        None => id.0.to_string(),
+    }
+}
+
+impl fmt::Display for EcsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EcsError::NoSuchEntity(entity) => write!(f, "entity {} does not exist", **entity),
+            EcsError::StaleEntity(entity) => write!(
+                f,
+                "entity {} generation {} was despawned",
+                **entity,
+                entity.generation()
+            ),
+            EcsError::AlreadyHasComponent(entity, id) => write!(
+                f,
+                "entity {} already has component {}",
+                **entity,
+                component_name(*id)
+            ),
+            EcsError::MissingComponent(entity, id) => write!(
+                f,
+                "entity {} does not have component {}",
+                **entity,
+                component_name(*id)
+            ),
+            EcsError::CapacityExceeded(err) => err.fmt(f),
+            EcsError::Deferred => write!(
+                f,
+                "storage cannot change while a system runs or a component or resource is borrowed"
+            ),
+        }
+    }
+}
+
+impl Error for EcsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            EcsError::CapacityExceeded(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<AllocError> for EcsError {
+    fn from(err: AllocError) -> Self {
+        EcsError::CapacityExceeded(err)
+    }
+}
+
+/// Returned by [`Schedule::try_run`](crate::Schedule::try_run) and
+/// [`Schedule::build`](crate::Schedule::build)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ScheduleError {
+    /// A `before` or `after` constraint names a label no system has
+    UnknownLabel(&'static str),
+    /// A `before` or `after` constraint of a system in `stage` names a label only systems of
+    /// other stages have. Stages always run in the order they were added, constraints only order
+    /// the systems within a stage.
+    LabelInOtherStage {
+        label: &'static str,
+        stage: &'static str,
+    },
+    /// The constraints of a stage contradict each other. The systems are listed in the order
+    /// they would have to run in, ending with the first one again.
+    Cycle {
+        stage: &'static str,
+        systems: Vec<&'static str>,
+    },
+    /// A system conflicts with a borrow held outside of the schedule
+    Borrow(BorrowError),
+}
+
+impl fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScheduleError::UnknownLabel(label) => write!(f, "no system has the label {}", label),
+            ScheduleError::LabelInOtherStage { label, stage } => write!(
+                f,
+                "no system in stage {} has the label {}, constraints only apply within a stage",
+                stage, label
+            ),
+            ScheduleError::Cycle { stage, systems } => write!(
+                f,
+                "systems {} form a cycle in stage {}",
+                systems.join(" -> "),
+                stage
+            ),
+            ScheduleError::Borrow(err) => err.fmt(f),
+        }
+    }
+}
+
+impl Error for ScheduleError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ScheduleError::Borrow(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<BorrowError> for ScheduleError {
+    fn from(err: BorrowError) -> Self {
+        ScheduleError::Borrow(err)
+    }
+}
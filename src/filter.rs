@@ -0,0 +1,261 @@
+use std::{marker::PhantomData, ops::Deref};
+
+use crate::{
+    archetype::Archetype, borrow::Access, change::SystemTicks, component::Component, table::Table,
+    ComponentState, Entity, Fetch, ParallelParam, QueryData, World,
+};
+
+/// Matches the entities that match any of the filters in the tuple, e.g.
+/// `Or<(With<A>, Changed<B>)>`
+pub struct Or<T> {
+    marker: PhantomData<T>,
+}
+
+/// Matches the entities that match at least one of the parameters in the tuple, and gives the
+/// items of the ones they match, e.g. `AnyOf<(&A, &mut B)>`. The tuple of options is wrapped:
+/// ```ignore
+///   world.run(|AnyOf((a, b)): AnyOf<(&A, &mut B)>| {
+///       ...
+///   });
+/// ```
+pub struct AnyOf<T: OptionTuple>(pub T::Options);
+
+/// Maps a tuple of parameters to the tuple of their options
+#[doc(hidden)]
+pub trait OptionTuple {
+    type Options;
+}
+
+/// Whether the entity has the component, which unlike `Option<&T>` does not borrow it
+pub struct Has<T: Component> {
+    has: bool,
+    marker: PhantomData<T>,
+}
+
+impl<T: Component> Deref for Has<T> {
+    type Target = bool;
+
+    fn deref(&self) -> &bool {
+        &self.has
+    }
+}
+
+unsafe impl<T: Component> ParallelParam for Has<T> {}
+
+unsafe impl<T: Component + 'static> QueryData for Has<T> {
+    type Key = Self;
+    type Item<'w> = Has<T>;
+    type ReadOnlyItem<'w> = Has<T>;
+    type State<'w> = ComponentState<'w>;
+    type Chunk<'w> = bool;
+    type ReadOnlyChunk<'w> = bool;
+
+    fn component_access(_: &mut Access) {}
+
+    fn match_archetype(_: &Archetype) -> bool {
+        true
+    }
+
+    #[allow(private_interfaces)]
+    fn state<'w>(world: &'w World, table: &'w Table, ticks: SystemTicks) -> ComponentState<'w> {
+        ComponentState::new::<T>(world, table, ticks)
+    }
+
+    #[inline(always)]
+    fn match_row(_: &ComponentState<'_>, _: Entity, _: usize) -> bool {
+        true
+    }
+
+    #[inline(always)]
+    unsafe fn item<'w>(state: &Self::State<'w>, entity: Entity, _: usize) -> Self::Item<'w> {
+        Has {
+            has: state.0.contains(entity),
+            marker: PhantomData,
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn read_only_item<'w>(
+        state: &Self::State<'w>,
+        entity: Entity,
+        _: usize,
+    ) -> Self::ReadOnlyItem<'w> {
+        Has {
+            has: state.0.contains(entity),
+            marker: PhantomData,
+        }
+    }
+
+    fn is_dense() -> bool {
+        !T::metadata_static().is_sparse()
+    }
+
+    #[inline(always)]
+    unsafe fn chunk<'w>(state: &Self::State<'w>, _: usize) -> Self::Chunk<'w> {
+        matches!(state.0, Fetch::Table(Some(_)))
+    }
+
+    #[inline(always)]
+    unsafe fn read_only_chunk<'w>(state: &Self::State<'w>, _: usize) -> Self::ReadOnlyChunk<'w> {
+        matches!(state.0, Fetch::Table(Some(_)))
+    }
+}
+
+// Every parameter's state is paired with whether the table's archetype matches it, rows of
+// tables it does not match never match it
+macro_rules! impl_filters {
+    ($(($p:ident, $idx:tt)),+) => {
+        impl<$($p,)+> OptionTuple for ($($p,)+) {
+            type Options = ($(Option<$p>,)+);
+        }
+
+        unsafe impl<$($p: ParallelParam,)+> ParallelParam for Or<($($p,)+)> {}
+
+        unsafe impl<$($p: ParallelParam,)+> ParallelParam for AnyOf<($($p,)+)> {}
+
+        unsafe impl<$($p: QueryData,)+> QueryData for Or<($($p,)+)> {
+            type Key = Or<($($p::Key,)+)>;
+            type Item<'w> = Or<($($p::Item<'w>,)+)>;
+            type ReadOnlyItem<'w> = Or<($($p::ReadOnlyItem<'w>,)+)>;
+            type State<'w> = ($((bool, $p::State<'w>),)+);
+            type Chunk<'w> = ();
+            type ReadOnlyChunk<'w> = ();
+
+            fn component_access(access: &mut Access) {
+                $($p::component_access(access);)+
+            }
+
+            fn match_archetype(archetype: &Archetype) -> bool {
+                $($p::match_archetype(archetype))||+
+            }
+
+            #[allow(private_interfaces)]
+            fn state<'w>(world: &'w World, table: &'w Table, ticks: SystemTicks) -> Self::State<'w> {
+                ($(($p::match_archetype(table.archetype()), $p::state(world, table, ticks)),)+)
+            }
+
+            #[inline(always)]
+            fn match_row(state: &Self::State<'_>, entity: Entity, index: usize) -> bool {
+                $((state.$idx.0 && $p::match_row(&state.$idx.1, entity, index)))||+
+            }
+
+            #[inline(always)]
+            unsafe fn item<'w>(_: &Self::State<'w>, _: Entity, _: usize) -> Self::Item<'w> {
+                Or {
+                    marker: PhantomData,
+                }
+            }
+
+            #[inline(always)]
+            unsafe fn read_only_item<'w>(
+                _: &Self::State<'w>,
+                _: Entity,
+                _: usize,
+            ) -> Self::ReadOnlyItem<'w> {
+                Or {
+                    marker: PhantomData,
+                }
+            }
+
+            fn is_dense() -> bool {
+                $($p::is_dense())&&+
+            }
+
+            #[inline(always)]
+            unsafe fn chunk<'w>(_: &Self::State<'w>, _: usize) -> Self::Chunk<'w> {}
+
+            #[inline(always)]
+            unsafe fn read_only_chunk<'w>(_: &Self::State<'w>, _: usize) -> Self::ReadOnlyChunk<'w> {}
+        }
+
+        unsafe impl<$($p: QueryData,)+> QueryData for AnyOf<($($p,)+)> {
+            type Key = AnyOf<($($p::Key,)+)>;
+            type Item<'w> = AnyOf<($($p::Item<'w>,)+)>;
+            type ReadOnlyItem<'w> = AnyOf<($($p::ReadOnlyItem<'w>,)+)>;
+            type State<'w> = ($((bool, $p::State<'w>),)+);
+            type Chunk<'w> = ($(Option<$p::Chunk<'w>>,)+);
+            type ReadOnlyChunk<'w> = ($(Option<$p::ReadOnlyChunk<'w>>,)+);
+
+            fn component_access(access: &mut Access) {
+                $($p::component_access(access);)+
+            }
+
+            fn match_archetype(archetype: &Archetype) -> bool {
+                $($p::match_archetype(archetype))||+
+            }
+
+            #[allow(private_interfaces)]
+            fn state<'w>(world: &'w World, table: &'w Table, ticks: SystemTicks) -> Self::State<'w> {
+                ($(($p::match_archetype(table.archetype()), $p::state(world, table, ticks)),)+)
+            }
+
+            #[inline(always)]
+            fn match_row(state: &Self::State<'_>, entity: Entity, index: usize) -> bool {
+                $((state.$idx.0 && $p::match_row(&state.$idx.1, entity, index)))||+
+            }
+
+            #[inline(always)]
+            unsafe fn item<'w>(
+                state: &Self::State<'w>,
+                entity: Entity,
+                index: usize,
+            ) -> Self::Item<'w> {
+                AnyOf(($(
+                    (state.$idx.0 && $p::match_row(&state.$idx.1, entity, index))
+                        .then(|| $p::item(&state.$idx.1, entity, index)),
+                )+))
+            }
+
+            #[inline(always)]
+            unsafe fn read_only_item<'w>(
+                state: &Self::State<'w>,
+                entity: Entity,
+                index: usize,
+            ) -> Self::ReadOnlyItem<'w> {
+                AnyOf(($(
+                    (state.$idx.0 && $p::match_row(&state.$idx.1, entity, index))
+                        .then(|| $p::read_only_item(&state.$idx.1, entity, index)),
+                )+))
+            }
+
+            fn is_dense() -> bool {
+                $($p::is_dense())&&+
+            }
+
+            #[inline(always)]
+            unsafe fn chunk<'w>(state: &Self::State<'w>, len: usize) -> Self::Chunk<'w> {
+                ($(state.$idx.0.then(|| $p::chunk(&state.$idx.1, len)),)+)
+            }
+
+            #[inline(always)]
+            unsafe fn read_only_chunk<'w>(state: &Self::State<'w>, len: usize) -> Self::ReadOnlyChunk<'w> {
+                ($(state.$idx.0.then(|| $p::read_only_chunk(&state.$idx.1, len)),)+)
+            }
+        }
+    }
+}
+
+impl_filters!((P1, 0), (P2, 1));
+impl_filters!((P1, 0), (P2, 1), (P3, 2));
+impl_filters!((P1, 0), (P2, 1), (P3, 2), (P4, 3));
+impl_filters!((P1, 0), (P2, 1), (P3, 2), (P4, 3), (P5, 4));
+impl_filters!((P1, 0), (P2, 1), (P3, 2), (P4, 3), (P5, 4), (P6, 5));
+impl_filters!(
+    (P1, 0),
+    (P2, 1),
+    (P3, 2),
+    (P4, 3),
+    (P5, 4),
+    (P6, 5),
+    (P7, 6)
+);
+impl_filters!(
+    (P1, 0),
+    (P2, 1),
+    (P3, 2),
+    (P4, 3),
+    (P5, 4),
+    (P6, 5),
+    (P7, 6),
+    (P8, 7)
+);
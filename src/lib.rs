@@ -1,17 +1,43 @@
 pub use ecs_codegen::component;
 
 pub mod archetype;
+pub mod borrow;
+pub mod change;
+mod commands;
 pub mod component;
+pub mod error;
+mod filter;
+pub mod query;
+mod removed;
+mod resource;
+pub mod schedule;
+mod sparse;
 mod table;
 mod test;
 
 use archetype::Archetype;
+use borrow::{Access, BorrowError, BorrowKey, Borrows, Ref, RefMut};
+pub use change::LastRun;
+use change::{ComponentTicks, SystemTicks};
+use commands::Cmd;
+pub use commands::{Commands, ParCommands};
 use component::{ComponentId, Metadata};
-use table::{Column, Table};
-
-use core::panic;
-use std::sync::atomic::AtomicUsize;
+use error::{AllocError, EcsError};
+pub use filter::{AnyOf, Has, Or};
+pub use query::{Query, QueryData};
+pub use removed::RemovedComponents;
+use removed::RemovedLog;
+use resource::{resource_id, ResourceData};
+pub use resource::{Res, ResMut, ResourceId};
+pub use schedule::Schedule;
+use sparse::SparseSet;
+use table::{Column, Edge, Table, TableId};
+
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::{
+    any::TypeId,
+    cell::Cell,
     collections::{BTreeSet, HashMap},
     marker::PhantomData,
     mem,
@@ -20,7 +46,7 @@ use std::{
 
 use crate::component::Component;
 
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub struct ArchetypeBuilder(Archetype);
 impl ArchetypeBuilder {
     #[allow(clippy::new_without_default)]
@@ -45,14 +71,34 @@ impl ArchetypeBuilder {
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
-pub struct Entity(pub u32);
+/// A handle to an entity: an index into the world's entity slots plus the generation of the slot
+/// at the time the handle was created. Slots are reused after `despawn`, and every reuse bumps the
+/// generation, so a handle that outlives its entity never resolves to the entity that took its slot.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct Entity {
+    index: u32,
+    generation: u32,
+}
+
+impl Entity {
+    pub const fn new(index: u32, generation: u32) -> Self {
+        Entity { index, generation }
+    }
+
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+}
 
 impl Deref for Entity {
     type Target = u32;
 
     fn deref(&self) -> &u32 {
-        &self.0
+        &self.index
     }
 }
 
@@ -79,114 +125,259 @@ impl Component for Entity {
     }
 }
 
-trait QueryParam<'a, T, A> {
-    fn access(world: &'a World, col: Option<&'a Column>, index: usize) -> A;
-    fn match_archetype(archetype: &Archetype) -> bool;
+/// Where a query parameter finds its component for the rows of one table
+pub(crate) enum Fetch<'a> {
+    Table(Option<&'a Column>),
+    Sparse(Option<&'a SparseSet>),
 }
 
-impl<'a, T: Component + 'static> QueryParam<'a, T, &'a T> for &'a T {
+impl<'a> Fetch<'a> {
     #[inline(always)]
-    fn access(_: &World, col: Option<&'a Column>, index: usize) -> &'a T {
-        unsafe { col.unwrap_unchecked().read::<T>(index) }
+    fn contains(&self, entity: Entity) -> bool {
+        match self {
+            Fetch::Table(col) => col.is_some(),
+            Fetch::Sparse(set) => set.is_some_and(|set| set.contains(entity)),
+        }
     }
 
-    fn match_archetype(archetype: &Archetype) -> bool {
-        archetype.contains(T::metadata_static())
+    #[inline(always)]
+    unsafe fn read<T: Component + 'static>(&self, entity: Entity, index: usize) -> Option<&'a T> {
+        match self {
+            Fetch::Table(col) => col.map(|col| col.read::<T>(index)),
+            Fetch::Sparse(set) => set.and_then(|set| set.get::<T>(entity)),
+        }
     }
-}
 
-impl<'a, T: Component + 'static> QueryParam<'a, T, &'a mut T> for &'a mut T {
+    /// Borrows the component mutably and marks it as changed at `tick`
     #[inline(always)]
-    fn access(_: &World, col: Option<&'a Column>, index: usize) -> &'a mut T {
-        unsafe { col.unwrap_unchecked().read_mut::<T>(index) }
+    unsafe fn read_mut<T: Component + 'static>(
+        &self,
+        entity: Entity,
+        index: usize,
+        tick: u32,
+    ) -> Option<&'a mut T> {
+        let ticks = self.ticks(entity, index)?;
+        ticks.set(ComponentTicks {
+            changed: tick,
+            ..ticks.get()
+        });
+        match self {
+            Fetch::Table(col) => col.map(|col| col.read_mut::<T>(index)),
+            Fetch::Sparse(set) => set.and_then(|set| set.get_mut::<T>(entity)),
+        }
     }
 
-    fn match_archetype(archetype: &Archetype) -> bool {
-        archetype.contains(T::metadata_static())
+    /// The items of the first `len` rows, if the component is stored in the table
+    #[inline(always)]
+    unsafe fn slice<T: Component + 'static>(&self, len: usize) -> Option<&'a [T]> {
+        match self {
+            Fetch::Table(col) => col.map(|col| col.slice::<T>(len)),
+            Fetch::Sparse(_) => None,
+        }
+    }
+
+    /// Same as `read_mut` for the first `len` rows
+    #[inline(always)]
+    unsafe fn slice_mut<T: Component + 'static>(
+        &self,
+        len: usize,
+        tick: u32,
+    ) -> Option<&'a mut [T]> {
+        match self {
+            Fetch::Table(Some(col)) => {
+                for index in 0..len {
+                    let ticks = col.ticks(index);
+                    ticks.set(ComponentTicks {
+                        changed: tick,
+                        ..ticks.get()
+                    });
+                }
+                Some(col.slice_mut::<T>(len))
+            }
+            _ => None,
+        }
     }
-}
 
-impl<'a, T: Component + 'static> QueryParam<'a, T, Option<&'a T>> for Option<&'a T> {
     #[inline(always)]
-    fn access(_: &World, col: Option<&'a Column>, index: usize) -> Option<&'a T> {
-        unsafe { col.map(|col| col.read::<T>(index)) }
+    unsafe fn ticks(&self, entity: Entity, index: usize) -> Option<&'a Cell<ComponentTicks>> {
+        match self {
+            Fetch::Table(col) => col.map(|col| col.ticks(index)),
+            Fetch::Sparse(set) => set.and_then(|set| set.ticks(entity)),
+        }
     }
+}
 
-    fn match_archetype(_: &Archetype) -> bool {
+/// Tables can only tell whether a sparse component might be there, so matching is done per
+/// table with `match_archetype` and then per row with `match_row`
+trait QueryParam<'a, T, A> {
+    /// What the parameter needs from the table being iterated
+    type State;
+    /// `Self` with `'static` lifetimes, see [`QueryData::Key`]
+    type Key: 'static;
+
+    fn state(world: &'a World, table: &'a Table, ticks: SystemTicks) -> Self::State;
+    /// The state of a parameter that does not depend on the entity, e.g. a resource. A system
+    /// whose parameters all have one runs once per run instead of once per matching entity, and
+    /// gets no entity to access.
+    fn run_state(_: &'a World, _: SystemTicks) -> Option<Self::State> {
+        None
+    }
+    fn access(state: &Self::State, entity: Entity, index: usize) -> A;
+    fn match_archetype(archetype: &Archetype) -> bool;
+    #[inline(always)]
+    fn match_row(_: &Self::State, _: Entity, _: usize) -> bool {
         true
     }
+    fn component_access(access: &mut Access);
+}
+
+/// Whether tables with this archetype can have rows with the component
+fn archetype_may_contain(archetype: &Archetype, metadata: Metadata) -> bool {
+    metadata.is_sparse() || archetype.contains(metadata)
 }
 
-impl<'a, T: Component + 'static> QueryParam<'a, T, Option<&'a mut T>> for Option<&'a mut T> {
+/// Where a single component is found in the table being iterated, and the ticks of the run
+#[doc(hidden)]
+pub struct ComponentState<'w>(Fetch<'w>, SystemTicks);
+
+impl<'w> ComponentState<'w> {
     #[inline(always)]
-    fn access(_: &World, col: Option<&'a Column>, index: usize) -> Option<&'a mut T> {
-        unsafe { col.map(|col| col.read_mut::<T>(index)) }
+    fn new<T: Component + 'static>(world: &'w World, table: &'w Table, ticks: SystemTicks) -> Self {
+        ComponentState(world.fetch::<T>(table), ticks)
     }
 
-    fn match_archetype(_: &Archetype) -> bool {
-        true
+    #[inline(always)]
+    fn is_added(&self, entity: Entity, index: usize) -> bool {
+        unsafe { self.0.ticks(entity, index) }.is_some_and(|t| self.1.is_newer(t.get().added))
     }
-}
 
-pub struct With<T: Component> {
-    marker: PhantomData<T>,
+    #[inline(always)]
+    fn is_changed(&self, entity: Entity, index: usize) -> bool {
+        unsafe { self.0.ticks(entity, index) }.is_some_and(|t| self.1.is_newer(t.get().changed))
+    }
 }
 
-impl<'a, T: Component + 'static> QueryParam<'a, T, With<T>> for With<T> {
+/// The component parameters are the items of their [`QueryData`], e.g. `&'a T` or
+/// `AnyOf<(&'a A, &'a mut B)>`
+impl<'a, Q: QueryData<Item<'a> = Q>> QueryParam<'a, (), Q> for Q {
+    type State = Q::State<'a>;
+    type Key = Q::Key;
+
+    fn state(world: &'a World, table: &'a Table, ticks: SystemTicks) -> Q::State<'a> {
+        Q::state(world, table, ticks)
+    }
+
     #[inline(always)]
-    fn access(_: &'a World, _: Option<&'a Column>, _: usize) -> With<T> {
-        With {
-            marker: PhantomData,
-        }
+    fn access(state: &Q::State<'a>, entity: Entity, index: usize) -> Q {
+        // Systems only access the rows that match, once each
+        unsafe { Q::item(state, entity, index) }
     }
 
     fn match_archetype(archetype: &Archetype) -> bool {
-        archetype.contains(T::metadata_static())
+        Q::match_archetype(archetype)
+    }
+
+    #[inline(always)]
+    fn match_row(state: &Q::State<'a>, entity: Entity, index: usize) -> bool {
+        Q::match_row(state, entity, index)
+    }
+
+    fn component_access(access: &mut Access) {
+        Q::component_access(access);
     }
 }
 
+pub struct With<T: Component> {
+    marker: PhantomData<T>,
+}
+
 pub struct Without<T: Component> {
     marker: PhantomData<T>,
 }
 
-impl<'a, T: Component + 'static> QueryParam<'a, T, Without<T>> for Without<T> {
-    #[inline(always)]
-    fn access(_: &'a World, _: Option<&'a Column>, _: usize) -> Without<T> {
-        Without {
-            marker: PhantomData,
-        }
-    }
+/// Matches the entities that got the component since the system last ran
+pub struct Added<T: Component> {
+    marker: PhantomData<T>,
+}
 
-    fn match_archetype(archetype: &Archetype) -> bool {
-        !archetype.contains(T::metadata_static())
-    }
+/// Matches the entities whose component was added or borrowed mutably since the system last ran
+pub struct Changed<T: Component> {
+    marker: PhantomData<T>,
 }
 
 pub trait System<'a, Params> {
-    fn run(&mut self, world: &'a World);
+    /// Declares the components the system reads and writes
+    fn access(&self, access: &mut Access);
+
+    /// # Safety
+    ///
+    /// The caller must hold the borrows declared by [`System::access`] for the duration of the call.
+    /// Use [`World::run`] instead.
+    unsafe fn run(&mut self, world: &'a World, ticks: SystemTicks);
 }
 
+/// Implemented by the system parameters that systems running on different threads at the same
+/// time can have: the components and resources read must be `Sync` and the ones written `Send`.
+/// Also implemented by the tuples of parameters [`System`] is implemented for. Required by
+/// [`Schedule::add_system`].
+///
+/// # Safety
+///
+/// The data the parameter gives access to must be safe to access from any thread
+pub unsafe trait ParallelParam {}
+
+unsafe impl<T: Component + Sync> ParallelParam for &T {}
+unsafe impl<T: Component + Send> ParallelParam for &mut T {}
+unsafe impl<T: Component + Sync> ParallelParam for Option<&T> {}
+unsafe impl<T: Component + Send> ParallelParam for Option<&mut T> {}
+// Filters only look at the archetype and the change ticks
+unsafe impl<T: Component> ParallelParam for With<T> {}
+unsafe impl<T: Component> ParallelParam for Without<T> {}
+unsafe impl<T: Component> ParallelParam for Added<T> {}
+unsafe impl<T: Component> ParallelParam for Changed<T> {}
+
 macro_rules! impl_system {
     ($(($param:ident, $t:ident, $col:ident)),+) => {
+        unsafe impl<$($param: ParallelParam,)+ $($t,)+> ParallelParam for ($($param,)+ $($t,)+) {}
+
         impl<'a, $($param,)+ $($t,)+ F> System<'a, ($($param,)+ $($t,)+)> for F
         where
-            $($t: Component + 'static,)+
             $($param: QueryParam<'a, $t, $param>,)+
             F: FnMut($($param,)+),
         {
-            fn run(&mut self, world: &'a World) {
+            fn access(&self, access: &mut Access) {
+                $($param::component_access(access);)+
+            }
+
+            unsafe fn run(&mut self, world: &'a World, ticks: SystemTicks) {
+                fn matches<'a, $($param,)+ $($t,)+>(archetype: &Archetype) -> bool
+                where
+                    $($param: QueryParam<'a, $t, $param>,)+
+                {
+                    $($param::match_archetype(archetype)) &&+ && true
+                }
+
+                if let ($(Some($col),)+) = ($($param::run_state(world, ticks),)+) {
+                    self($($param::access(&$col, Entity::new(0, 0), 0),)+);
+                    return;
+                }
+
                 unsafe {
-                    for (archetype, table) in world.inner().tables.iter_mut() {
-                        if $($param::match_archetype(archetype)) &&+ && true {
-                            let len = table.len();
-                            let entities = table.get_column::<Entity>().unwrap_unchecked();
-                            $(let $col= table.get_column::<$t>();)+
-                            for item_idx in 0..len {
-                                if entities.read::<Entity>(item_idx).0 != 0 {
-                                    self(
-                                        $($param::access(world, $col, item_idx),)+
-                                    );
-                                }
+                    let table_ids = world.matching_tables::<($($param::Key,)+)>(
+                        matches::<$($param,)+ $($t,)+>,
+                    );
+                    for table_id in table_ids.iter() {
+                        let table = world.table(*table_id);
+                        let len = table.len();
+                        if len == 0 {
+                            continue;
+                        }
+                        let entities = table.get_column::<Entity>().unwrap_unchecked();
+                        $(let $col = $param::state(world, table, ticks);)+
+                        for item_idx in 0..len {
+                            let entity = *entities.read::<Entity>(item_idx);
+                            if $($param::match_row(&$col, entity, item_idx)) &&+ {
+                                self($($param::access(&$col, entity, item_idx),)+);
                             }
                         }
                     }
@@ -354,8 +545,12 @@ impl_system!(
 
 pub trait Bundle {
     fn set_archetype(&self, archetype: &mut Archetype);
+    fn bundle_metadata() -> Vec<Metadata>
+    where
+        Self: Sized;
     #[allow(private_interfaces)]
-    fn write_self_to_table(self, index: usize, table: &mut Table);
+    fn write_self_to_world(self, world: &World, entity: Entity, table_id: TableId, index: usize);
+    fn into_boxes(self) -> Vec<Box<dyn Component>>;
 }
 
 macro_rules! impl_bundle {
@@ -365,28 +560,44 @@ macro_rules! impl_bundle {
             $($t: Component + 'static,)+
         {
             fn set_archetype(&self, archetype: &mut Archetype) {
-                $(archetype.set(self.$idx.metadata());)+
+                $(set_table_component(archetype, self.$idx.metadata());)+
+            }
+
+            fn bundle_metadata() -> Vec<Metadata> {
+                vec![$($t::metadata_static(),)+]
             }
 
             #[allow(private_interfaces)]
-            fn write_self_to_table(self, index: usize, table: &mut Table) {
-                unsafe { $(table.write_any(self.$idx.metadata(), index, &self.$idx);)+ };
+            fn write_self_to_world(self, world: &World, entity: Entity, table_id: TableId, index: usize) {
+                unsafe { $(world.write_component(entity, table_id, index, &self.$idx);)+ };
                 mem::forget(self);
             }
+
+            fn into_boxes(self) -> Vec<Box<dyn Component>> {
+                vec![$(Box::new(self.$idx),)+]
+            }
         }
     }
 }
 
 impl<T1: Component + 'static> Bundle for T1 {
     fn set_archetype(&self, archetype: &mut Archetype) {
-        archetype.set(self.metadata());
+        set_table_component(archetype, self.metadata());
+    }
+
+    fn bundle_metadata() -> Vec<Metadata> {
+        vec![T1::metadata_static()]
     }
 
     #[allow(private_interfaces)]
-    fn write_self_to_table(self, index: usize, table: &mut Table) {
-        unsafe { table.write_any(self.metadata(), index, &self) };
+    fn write_self_to_world(self, world: &World, entity: Entity, table_id: TableId, index: usize) {
+        unsafe { world.write_component(entity, table_id, index, &self) };
         mem::forget(self);
     }
+
+    fn into_boxes(self) -> Vec<Box<dyn Component>> {
+        vec![Box::new(self)]
+    }
 }
 
 // XXX
@@ -470,21 +681,80 @@ impl_bundle!(
     (T12, 11)
 );
 
-enum Cmd {
-    AddComponent((Entity, Metadata, Box<dyn Component>)),
-    RemoveComponent((Entity, Metadata)),
+/// Keeps the panicking behaviour of the methods that have a `try_` variant
+fn panic_on_alloc(err: EcsError) -> EcsError {
+    if let EcsError::CapacityExceeded(err) = err {
+        panic!("{}", err);
+    }
+    err
+}
+
+/// Sparse components are stored outside of tables and are left out of archetypes
+fn set_table_component(archetype: &mut Archetype, metadata: Metadata) {
+    if !metadata.is_sparse() {
+        archetype.set(metadata);
+    }
+}
+
+/// Frees the box without dropping its contents, for when they were moved into a table
+fn free_box(component: Box<dyn Component>) {
+    let layout = std::alloc::Layout::for_value(&*component);
+    let ptr = Box::into_raw(component);
+    if layout.size() != 0 {
+        unsafe { std::alloc::dealloc(ptr.cast(), layout) };
+    }
+}
+
+#[derive(Clone, Copy)]
+struct EntityMeta {
+    generation: u32,
+    location: Option<(TableId, usize)>,
+}
+
+impl EntityMeta {
+    const EMPTY: EntityMeta = EntityMeta {
+        generation: 0,
+        location: None,
+    };
+}
+
+/// The tables a query matched as of `generation`, the number of tables that existed then
+struct QueryCache {
+    generation: usize,
+    tables: Arc<[TableId]>,
 }
 
+type CommandErrorHandler = Box<dyn FnMut(EcsError) + Send>;
+
 struct WorldInner {
-    entities: Vec<Option<(Archetype, usize)>>,
-    tables: HashMap<Archetype, Table>,
-    free_entities: BTreeSet<Entity>,
-    cmd_queue: Vec<Cmd>,
+    entities: Vec<EntityMeta>,
+    tables: Vec<Table>,
+    table_ids: HashMap<Archetype, TableId>,
+    // Keyed by the query or system parameters, see `World::matching_tables`
+    query_cache: Mutex<HashMap<TypeId, QueryCache>>,
+    // Indexed by component id, grown on demand
+    sparse_sets: Vec<Option<SparseSet>>,
+    // Indexed by component id, only the components whose removals are tracked have a log
+    removed: Mutex<Vec<Option<RemovedLog>>>,
+    // Indexed by resource id, see `resource::resource_id`
+    resources: Vec<Option<ResourceData>>,
+    free_entities: BTreeSet<u32>,
+    cmd_queue: Mutex<Vec<Cmd>>,
+    command_error_handler: Mutex<Option<CommandErrorHandler>>,
+    // Ids handed out by `Commands::spawn` past the end of `entities`, see `World::reserve_entity`
+    num_reserved_entities: AtomicU32,
     num_systems_running: AtomicUsize,
+    borrows: Borrows,
+    // Advanced by every run, see `World::advance_tick`
+    change_tick: AtomicU32,
 }
 
+/// A `World` either owns its state, in which case dropping it frees every table and runs the
+/// destructors of all components, or is a non-owning view of a world owned elsewhere (see
+/// [`World::from_raw`]), in which case dropping it does nothing.
 pub struct World {
     inner: *mut WorldInner,
+    owned: bool,
 }
 
 unsafe impl Send for World {}
@@ -495,26 +765,83 @@ impl World {
     pub fn new() -> Self {
         World {
             inner: Box::into_raw(Box::new(WorldInner {
-                // Entity(0) is used to mark deleted columns
-                entities: vec![None],
-                tables: HashMap::new(),
+                // Slot 0 is reserved, no entity is ever allocated in it
+                entities: vec![EntityMeta::EMPTY],
+                tables: Vec::new(),
+                table_ids: HashMap::new(),
+                query_cache: Mutex::default(),
+                sparse_sets: Vec::new(),
+                removed: Mutex::default(),
+                resources: Vec::new(),
                 free_entities: BTreeSet::new(),
-                cmd_queue: Vec::default(),
+                cmd_queue: Mutex::default(),
+                command_error_handler: Mutex::default(),
+                num_reserved_entities: AtomicU32::new(0),
                 num_systems_running: AtomicUsize::new(0),
+                borrows: Borrows::default(),
+                // Tick 0 is the last run of systems that never ran
+                change_tick: AtomicU32::new(1),
             })),
+            owned: true,
         }
     }
 
+    /// Creates a non-owning view of the world behind `ptr`. Dropping the view leaves the world intact.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been obtained from [`World::as_raw`] or [`World::into_raw`] and the world it
+    /// points to must outlive the returned view
     pub unsafe fn from_raw(ptr: *mut u8) -> Self {
-        World { inner: ptr.cast() }
+        World {
+            inner: ptr.cast(),
+            owned: false,
+        }
     }
 
+    /// Takes ownership of the world behind `ptr`. Dropping the returned world frees it.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been obtained from [`World::into_raw`] and ownership must not be taken twice
+    pub unsafe fn from_raw_owned(ptr: *mut u8) -> Self {
+        World {
+            inner: ptr.cast(),
+            owned: true,
+        }
+    }
+
+    /// Returns a pointer to this world's state without giving up ownership
+    ///
+    /// # Safety
+    ///
+    /// The returned pointer is only valid while this world is alive
     pub unsafe fn as_raw(&self) -> *mut u8 {
         self.inner.cast()
     }
 
+    /// Releases ownership of this world's state. Use [`World::from_raw_owned`] to free it again.
+    pub fn into_raw(self) -> *mut u8 {
+        let ptr = self.inner.cast();
+        mem::forget(self);
+        ptr
+    }
+
+    /// Turns this world into a non-owning view of the world behind `ptr`, freeing the state it
+    /// owned before, if any. Does nothing if `ptr` is this world's own state.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`World::from_raw`]
     pub unsafe fn set_inner_from_raw(&mut self, ptr: *mut u8) {
-        self.inner = ptr.cast();
+        if ptr == self.inner.cast() {
+            return;
+        }
+        *self = World::from_raw(ptr);
+    }
+
+    pub fn is_owned(&self) -> bool {
+        self.owned
     }
 
     #[allow(clippy::mut_from_ref)]
@@ -522,18 +849,421 @@ impl World {
         unsafe { &mut *self.inner }
     }
 
-    // TODO make this safer using a spawn queue?
-    // TODO flesh out this doc
-    /// # SAFETY
-    ///
-    /// Spawning inside a system where the archetype of the spawned entity is a subset of the query archetype
-    /// is problematic
-    pub unsafe fn spawn<B: Bundle>(&self, bundle: B) -> Entity {
-        let entity = self
+    /// Same as `location`, but tells why the entity has no location
+    fn entity_location(&self, entity: Entity) -> Result<(TableId, usize), EcsError> {
+        match self.inner().entities.get(*entity as usize) {
+            Some(meta) if meta.generation == entity.generation => {
+                meta.location.ok_or(EcsError::NoSuchEntity(entity))
+            }
+            Some(_) => Err(EcsError::StaleEntity(entity)),
+            None => Err(EcsError::NoSuchEntity(entity)),
+        }
+    }
+
+    /// Returns the location of `entity` if it is alive and the handle is not stale
+    fn location(&self, entity: Entity) -> Option<(TableId, usize)> {
+        match self.inner().entities.get(*entity as usize) {
+            Some(meta) if meta.generation == entity.generation => meta.location,
+            _ => None,
+        }
+    }
+
+    /// Fails if the slot of `entity` was reused since the handle was handed out, in which case
+    /// writing to the slot would bring the handle back to life and kill the live entity
+    fn check_generation(&self, entity: Entity) -> Result<(), EcsError> {
+        match self.inner().entities.get(*entity as usize) {
+            Some(meta) if meta.generation != entity.generation => {
+                Err(EcsError::StaleEntity(entity))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn location_mut(&self, entity: Entity) -> Option<&mut (TableId, usize)> {
+        match self.inner().entities.get_mut(*entity as usize) {
+            Some(meta) if meta.generation == entity.generation => meta.location.as_mut(),
+            _ => None,
+        }
+    }
+
+    fn alloc_entity(&self) -> Entity {
+        self.materialize_reserved_entities();
+        match self.inner().free_entities.pop_first() {
+            Some(index) => Entity::new(index, self.inner().entities[index as usize].generation),
+            None => {
+                self.inner().entities.push(EntityMeta::EMPTY);
+                Entity::new(self.inner().entities.len() as u32 - 1, 0)
+            }
+        }
+    }
+
+    /// Hands out an entity id without touching `entities` so it can be called while systems run.
+    /// The slots are only created by `materialize_reserved_entities` once the world is idle.
+    fn reserve_entity(&self) -> Entity {
+        let offset = self
             .inner()
-            .free_entities
-            .pop_first()
-            .unwrap_or(Entity(self.inner().entities.len() as u32));
+            .num_reserved_entities
+            .fetch_add(1, Ordering::Relaxed);
+        Entity::new(self.inner().entities.len() as u32 + offset, 0)
+    }
+
+    fn materialize_reserved_entities(&self) {
+        let n = self
+            .inner()
+            .num_reserved_entities
+            .swap(0, Ordering::Relaxed);
+        if n > 0 {
+            let len = self.inner().entities.len();
+            self.inner()
+                .entities
+                .resize(len + n as usize, EntityMeta::EMPTY);
+        }
+    }
+
+    fn push_cmd(&self, cmd: Cmd) {
+        self.inner().cmd_queue.lock().unwrap().push(cmd);
+    }
+
+    fn append_cmds(&self, cmds: Vec<Cmd>) {
+        self.inner().cmd_queue.lock().unwrap().extend(cmds);
+    }
+
+    pub fn commands(&self) -> Commands<'_> {
+        Commands::new(self)
+    }
+
+    fn set_location(&self, entity: Entity, location: (TableId, usize)) {
+        self.inner().entities[*entity as usize] = EntityMeta {
+            generation: entity.generation,
+            location: Some(location),
+        };
+    }
+
+    pub fn is_alive(&self, entity: Entity) -> bool {
+        self.location(entity).is_some()
+    }
+
+    /// Structural changes are deferred while a system runs or a component is borrowed, since they
+    /// could move or drop the data behind the borrow
+    fn is_deferring(&self) -> bool {
+        self.inner()
+            .num_systems_running
+            .load(std::sync::atomic::Ordering::Relaxed)
+            > 0
+            || self.inner().borrows.is_borrowed()
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    #[inline(always)]
+    fn table(&self, table_id: TableId) -> &mut Table {
+        unsafe { self.inner().tables.get_unchecked_mut(table_id) }
+    }
+
+    /// Returns the id of the table storing `archetype`, creating an empty one if needed
+    fn table_id_or_insert(&self, archetype: Archetype) -> TableId {
+        if let Some(table_id) = self.inner().table_ids.get(&archetype) {
+            return *table_id;
+        }
+        self.insert_table(Table::new(archetype))
+    }
+
+    fn insert_table(&self, table: Table) -> TableId {
+        let table_id = self.inner().tables.len();
+        self.inner()
+            .table_ids
+            .insert(table.archetype().clone(), table_id);
+        self.inner().tables.push(table);
+        table_id
+    }
+
+    fn sparse_set(&self, id: ComponentId) -> Option<&mut SparseSet> {
+        self.inner()
+            .sparse_sets
+            .get_mut(id.0 as usize)
+            .and_then(Option::as_mut)
+    }
+
+    fn sparse_set_or_insert(&self, metadata: Metadata) -> &mut SparseSet {
+        let sets = &mut self.inner().sparse_sets;
+        let id = metadata.id().0 as usize;
+        if id >= sets.len() {
+            sets.resize_with(id + 1, || None);
+        }
+        sets[id].get_or_insert_with(|| SparseSet::new(metadata))
+    }
+
+    /// Drops every sparse component of the entity in the slot, regardless of its generation
+    fn remove_sparse_components(&self, entity: Entity) {
+        let mut removed = self.inner().removed.lock().unwrap();
+        for (id, set) in self.inner().sparse_sets.iter_mut().enumerate() {
+            if let Some(set) = set {
+                set.remove_into(entity, removed.get_mut(id).and_then(Option::as_mut));
+            }
+        }
+    }
+
+    /// Writes the component of a freshly spawned entity to its table row or sparse set
+    ///
+    /// # Safety
+    ///
+    /// The caller must not drop `component` afterwards
+    unsafe fn write_component(
+        &self,
+        entity: Entity,
+        table_id: TableId,
+        index: usize,
+        component: &dyn Component,
+    ) {
+        let metadata = component.metadata();
+        if metadata.is_sparse() {
+            let set = self.sparse_set_or_insert(metadata);
+            set.remove(entity);
+            set.insert_any(entity, component, self.change_tick());
+        } else {
+            self.table(table_id)
+                .write_any(metadata, index, component, self.change_tick());
+        }
+    }
+
+    /// The tick stamped on components added or borrowed mutably outside of systems
+    #[inline(always)]
+    fn change_tick(&self) -> u32 {
+        self.inner().change_tick.load(Ordering::Relaxed)
+    }
+
+    /// Advances the world tick for a run of a system whose previous run was at `last_run`
+    fn advance_tick(&self, last_run: u32) -> SystemTicks {
+        let this_run = self.inner().change_tick.fetch_add(1, Ordering::Relaxed);
+        SystemTicks { last_run, this_run }
+    }
+
+    #[inline(always)]
+    fn fetch<'w, T: Component + 'static>(&'w self, table: &'w Table) -> Fetch<'w> {
+        let metadata = T::metadata_static();
+        if metadata.is_sparse() {
+            Fetch::Sparse(self.sparse_set(metadata.id()).map(|set| &*set))
+        } else {
+            Fetch::Table(unsafe { table.get_column_by_id(metadata.id()) })
+        }
+    }
+
+    /// Returns the tables whose archetype `matches` accepts, where `K` is the `'static` key of
+    /// the query or system parameters `matches` is for. The result is cached per key and tables
+    /// are never removed, so only the tables created since the last call are checked.
+    fn matching_tables<K: 'static>(&self, matches: fn(&Archetype) -> bool) -> Arc<[TableId]> {
+        let tables = &self.inner().tables;
+        let mut cache = self.inner().query_cache.lock().unwrap();
+        let entry = cache
+            .entry(TypeId::of::<K>())
+            .or_insert_with(|| QueryCache {
+                generation: 0,
+                tables: Arc::new([]),
+            });
+
+        if entry.generation < tables.len() {
+            let new =
+                (entry.generation..tables.len()).filter(|id| matches(tables[*id].archetype()));
+            let mut matched = entry.tables.to_vec();
+            let num_matched = matched.len();
+            matched.extend(new);
+            if matched.len() > num_matched {
+                entry.tables = matched.into();
+            }
+            entry.generation = tables.len();
+        }
+
+        entry.tables.clone()
+    }
+
+    /// Returns the id of the table storing `archetype`. If it does not exist yet it is created
+    /// with the columns it shares with the table `src_id`, which an entity is about to move from.
+    fn migration_target(&self, archetype: Archetype, src_id: TableId) -> TableId {
+        if let Some(table_id) = self.inner().table_ids.get(&archetype) {
+            return *table_id;
+        }
+
+        let table = self.table(src_id);
+        let mut new_table = Table::new(archetype);
+        for id in table.archetype().ids() {
+            unsafe {
+                if let Some(col) = table.get_column_by_id(ComponentId(id as u32)) {
+                    if new_table.archetype().contains_id(id) {
+                        new_table.add_column_by_id(
+                            ComponentId(id as u32),
+                            col.item_layout(),
+                            col.get_drop_fn(),
+                        )
+                    }
+                }
+            };
+        }
+        self.insert_table(new_table)
+    }
+
+    /// Returns the cached transition from the table when adding the component, building it and
+    /// the reverse remove edge the first time. `None` if the table already has the component.
+    fn add_edge(&self, table_id: TableId, metadata: Metadata) -> Option<Edge> {
+        let table = self.table(table_id);
+        if let Some(edge) = table.add_edge(metadata.id()) {
+            return Some(edge.clone());
+        }
+        if table.archetype().contains(metadata) {
+            return None;
+        }
+
+        let mut archetype = table.archetype().clone();
+        archetype.set(metadata);
+        let (shared, zero_sized) = self.columns_to_copy(table, table.archetype());
+        let target = self.migration_target(archetype, table_id);
+
+        let edge = Edge {
+            target,
+            shared,
+            zero_sized,
+        };
+        self.table(table_id)
+            .set_add_edge(metadata.id(), edge.clone());
+        self.table(target).set_remove_edge(
+            metadata.id(),
+            Edge {
+                target: table_id,
+                shared: edge.shared.clone(),
+                zero_sized: edge.zero_sized.clone(),
+            },
+        );
+        Some(edge)
+    }
+
+    /// Same as `add_edge` for removing the component. `None` if the table does not have it.
+    fn remove_edge(&self, table_id: TableId, metadata: Metadata) -> Option<Edge> {
+        let table = self.table(table_id);
+        if let Some(edge) = table.remove_edge(metadata.id()) {
+            return Some(edge.clone());
+        }
+        if !table.archetype().contains(metadata) {
+            return None;
+        }
+
+        let mut archetype = table.archetype().clone();
+        archetype.unset(metadata);
+        let (shared, zero_sized) = self.columns_to_copy(table, &archetype);
+        let target = self.migration_target(archetype, table_id);
+
+        let edge = Edge {
+            target,
+            shared,
+            zero_sized,
+        };
+        self.table(table_id)
+            .set_remove_edge(metadata.id(), edge.clone());
+        self.table(target).set_add_edge(
+            metadata.id(),
+            Edge {
+                target: table_id,
+                shared: edge.shared.clone(),
+                zero_sized: edge.zero_sized.clone(),
+            },
+        );
+        Some(edge)
+    }
+
+    /// Returns the columns of `archetype` that hold data in the table, and separately the
+    /// zero-sized ones which only have change ticks to copy
+    fn columns_to_copy(
+        &self,
+        table: &Table,
+        archetype: &Archetype,
+    ) -> (Arc<[ComponentId]>, Arc<[ComponentId]>) {
+        let (zero_sized, shared): (Vec<_>, Vec<_>) = archetype
+            .ids()
+            .map(|id| ComponentId(id as u32))
+            .filter_map(|id| unsafe { Some((id, table.get_column_by_id(id)?.is_zero_sized())) })
+            .partition(|(_, is_zero_sized)| *is_zero_sized);
+        let ids = |cols: Vec<(ComponentId, bool)>| cols.into_iter().map(|(id, _)| id).collect();
+        (ids(shared), ids(zero_sized))
+    }
+
+    /// Copies the shared columns of the entity's row over to a new row in the edge's target table
+    /// and returns the new row. The old row is left for the caller to remove.
+    fn migrate_row(&self, table_id: TableId, index: usize, edge: &Edge) -> usize {
+        let table = self.table(table_id);
+        let new_table = self.table(edge.target);
+        let new_index = new_table.reserve_index();
+
+        // Zero-sized items are not copied, only their ticks
+        for id in edge.shared.iter().chain(edge.zero_sized.iter()) {
+            unsafe {
+                Column::copy_item_from_column(
+                    table.get_column_by_id_mut(*id).unwrap_unchecked(),
+                    new_table.get_column_by_id_mut(*id).unwrap_unchecked(),
+                    index,
+                    new_index,
+                );
+            }
+        }
+
+        new_index
+    }
+
+    /// Makes room for `additional` rows in the table and in the sparse sets of the sparse
+    /// components in `metadata`, and for entity slots up to `max_index`. Done before writing an
+    /// entity so that writing it cannot fail halfway.
+    fn try_reserve_storage(
+        &self,
+        table_id: TableId,
+        metadata: &[Metadata],
+        additional: usize,
+        max_index: usize,
+    ) -> Result<(), AllocError> {
+        self.table(table_id).try_reserve(metadata, additional)?;
+        for metadata in metadata.iter().filter(|metadata| metadata.is_sparse()) {
+            self.sparse_set_or_insert(*metadata)
+                .try_reserve(additional, max_index)?;
+        }
+
+        let entities = &mut self.inner().entities;
+        entities.try_reserve((max_index + 1).saturating_sub(entities.len()))?;
+        Ok(())
+    }
+
+    /// Makes room for `additional` entities with the components of `B`, so that spawning them
+    /// does not allocate. Fails with [`EcsError::Deferred`] while a system runs or a component is
+    /// borrowed, since growing the storage would move the borrowed items.
+    pub fn try_reserve<B: Bundle>(&self, additional: usize) -> Result<(), EcsError> {
+        if self.is_deferring() {
+            return Err(EcsError::Deferred);
+        }
+
+        let metadata = B::bundle_metadata();
+        let mut archetype = Archetype::new();
+        for metadata in &metadata {
+            set_table_component(&mut archetype, *metadata);
+        }
+        archetype.set(Entity::metadata_static());
+
+        let table_id = self.table_id_or_insert(archetype);
+        self.materialize_reserved_entities();
+        let max_index = self.inner().entities.len() + additional;
+        Ok(self.try_reserve_storage(table_id, &metadata, additional, max_index)?)
+    }
+
+    /// Spawns an entity right away, or reserves its id and defers the spawn like
+    /// [`Commands::spawn`] while a system runs or a component is borrowed
+    ///
+    /// # Panics
+    ///
+    /// If allocating storage for the entity fails, see [`World::try_spawn`]
+    pub fn spawn<B: Bundle>(&self, bundle: B) -> Entity {
+        self.try_spawn(bundle)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Same as [`World::spawn`], but returns an error instead of panicking if storage for the
+    /// entity cannot be allocated. The world is left unchanged in that case.
+    pub fn try_spawn<B: Bundle>(&self, bundle: B) -> Result<Entity, EcsError> {
+        if self.is_deferring() {
+            return Ok(self.commands().spawn(bundle));
+        }
 
         let mut archetype = Archetype::new();
 
@@ -541,389 +1271,581 @@ impl World {
 
         archetype.set(Entity::metadata_static());
 
-        self.inner()
-            .tables
-            .entry(archetype)
-            .or_insert_with(Table::new);
+        let table_id = self.table_id_or_insert(archetype);
+        self.materialize_reserved_entities();
+        let max_index = self.inner().entities.len();
+        self.try_reserve_storage(table_id, &B::bundle_metadata(), 1, max_index)?;
 
-        let table = unsafe { self.inner().tables.get_mut(&archetype).unwrap_unchecked() };
+        let entity = self.alloc_entity();
+        let table = self.table(table_id);
         let index = table.reserve_index();
         unsafe { table.write::<Entity>(index, entity) };
-        bundle.write_self_to_table(index, table);
-        match self.inner().entities.get_mut(*entity as usize) {
-            Some(p) => *p = Some((archetype, index)),
-            None => self.inner().entities.push(Some((archetype, index))),
-        }
+        bundle.write_self_to_world(self, entity, table_id, index);
+        self.set_location(entity, (table_id, index));
 
-        entity
+        Ok(entity)
     }
 
     // FIXME this shares a ton of code with spawn()
-    pub fn spawn_from_slice_of_boxes(&self, bundle: &[Box<dyn Component>]) -> Entity {
-        let entity = self
-            .inner()
-            .free_entities
-            .pop_first()
-            .unwrap_or(Entity(self.inner().entities.len() as u32));
+    pub fn spawn_from_slice_of_boxes(&self, bundle: Vec<Box<dyn Component>>) -> Entity {
+        if self.is_deferring() {
+            let entity = self.reserve_entity();
+            self.push_cmd(Cmd::Insert((entity, bundle)));
+            return entity;
+        }
+
+        let entity = self.alloc_entity();
 
         let mut archetype = Archetype::new();
 
-        for item in bundle {
-            archetype.set(item.metadata());
+        for item in &bundle {
+            set_table_component(&mut archetype, item.metadata());
         }
 
         archetype.set(Entity::metadata_static());
 
-        self.inner()
-            .tables
-            .entry(archetype)
-            .or_insert_with(Table::new);
-
-        let table = unsafe { self.inner().tables.get_mut(&archetype).unwrap_unchecked() };
+        let table_id = self.table_id_or_insert(archetype);
+        let table = self.table(table_id);
         let index = table.reserve_index();
         unsafe { table.write::<Entity>(index, entity) };
         for item in bundle {
-            unsafe { table.write_any(item.metadata(), index, &**item) };
-        }
-        match self.inner().entities.get_mut(*entity as usize) {
-            Some(p) => *p = Some((archetype, index)),
-            None => self.inner().entities.push(Some((archetype, index))),
+            unsafe { self.write_component(entity, table_id, index, &*item) };
+            free_box(item);
         }
+        self.set_location(entity, (table_id, index));
 
         entity
     }
 
+    /// # Panics
+    ///
+    /// If the handle is stale or allocating storage for the entity fails, see
+    /// [`World::try_insert`]
     // TODO tests
-    // TODO this is almost identical to spawn(). dedup
     pub fn insert<B: Bundle>(&self, entity: Entity, bundle: B) -> Entity {
+        self.try_insert(entity, bundle)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Same as [`World::insert`], but returns an error instead of panicking if the slot of the
+    /// entity was reused since the handle was handed out, or if storage for the entity cannot be
+    /// allocated. The world is left unchanged in both cases.
+    // TODO this is almost identical to spawn(). dedup
+    pub fn try_insert<B: Bundle>(&self, entity: Entity, bundle: B) -> Result<Entity, EcsError> {
+        if self.is_deferring() {
+            self.commands().insert(entity, bundle);
+            return Ok(entity);
+        }
+
         let mut archetype = Archetype::new();
 
         bundle.set_archetype(&mut archetype);
 
         archetype.set(Entity::metadata_static());
 
-        self.inner()
-            .tables
-            .entry(archetype)
-            .or_insert_with(Table::new);
+        self.materialize_reserved_entities();
+        self.check_generation(entity)?;
+        let table_id = self.table_id_or_insert(archetype);
+        self.try_reserve_storage(table_id, &B::bundle_metadata(), 1, *entity as usize)?;
 
-        let table = unsafe { self.inner().tables.get_mut(&archetype).unwrap_unchecked() };
+        self.clear_slot(entity);
+
+        let table = self.table(table_id);
         let index = table.reserve_index();
         unsafe { table.write::<Entity>(index, entity) };
-        bundle.write_self_to_table(index, table);
+        bundle.write_self_to_world(self, entity, table_id, index);
 
         if *entity as usize >= self.inner().entities.len() {
-            self.inner().entities.resize(*entity as usize + 1, None);
+            self.inner()
+                .entities
+                .resize(*entity as usize + 1, EntityMeta::EMPTY);
             // TODO add the slots in the gap to the free list
         }
-        self.inner().entities.as_mut_slice()[*entity as usize] = Some((archetype, index));
-        self.inner().free_entities.remove(&entity);
+        self.set_location(entity, (table_id, index));
+        self.inner().free_entities.remove(&entity.index);
 
-        entity
+        Ok(entity)
     }
 
     // TODO ton of code shared again...
     pub fn insert_from_slice_of_boxes(
         &self,
         entity: Entity,
-        bundle: &[Box<dyn Component>],
-    ) -> Entity {
+        bundle: Vec<Box<dyn Component>>,
+    ) -> Result<Entity, EcsError> {
+        if self.is_deferring() {
+            self.push_cmd(Cmd::Insert((entity, bundle)));
+            return Ok(entity);
+        }
+
+        self.materialize_reserved_entities();
+        self.check_generation(entity)?;
+        self.clear_slot(entity);
+
         let mut archetype = Archetype::new();
 
-        for item in bundle {
-            archetype.set(item.metadata());
+        for item in &bundle {
+            set_table_component(&mut archetype, item.metadata());
         }
 
         archetype.set(Entity::metadata_static());
 
-        self.inner()
-            .tables
-            .entry(archetype)
-            .or_insert_with(Table::new);
-
-        let table = unsafe { self.inner().tables.get_mut(&archetype).unwrap_unchecked() };
+        let table_id = self.table_id_or_insert(archetype);
+        let table = self.table(table_id);
         let index = table.reserve_index();
         unsafe { table.write::<Entity>(index, entity) };
         for item in bundle {
-            unsafe { table.write_any(item.metadata(), index, &**item) };
+            unsafe { self.write_component(entity, table_id, index, &*item) };
+            free_box(item);
         }
-        match self.inner().entities.get_mut(*entity as usize) {
-            Some(p) => *p = Some((archetype, index)),
-            None => self.inner().entities.push(Some((archetype, index))),
+        if *entity as usize >= self.inner().entities.len() {
+            self.inner()
+                .entities
+                .resize(*entity as usize + 1, EntityMeta::EMPTY);
         }
+        self.set_location(entity, (table_id, index));
+        self.inner().free_entities.remove(&entity.index);
 
-        entity
+        Ok(entity)
     }
 
-    pub fn despawn(&self, entity: Entity) {
-        if entity == Entity(0) {
-            return;
+    /// Drops the entity's components and frees its slot. While a system runs or a component is
+    /// borrowed the despawn is deferred and always succeeds, a failure is then reported to the
+    /// handler set with [`World::on_command_error`].
+    pub fn despawn(&self, entity: Entity) -> Result<(), EcsError> {
+        if self.is_deferring() {
+            self.commands().despawn(entity);
+            return Ok(());
         }
 
-        if let Some(Some((archetype, index))) = self.inner().entities.get(*entity as usize) {
-            let table = unsafe { self.inner().tables.get_mut(archetype).unwrap_unchecked() };
-
-            *unsafe { table.read_mut::<Entity>(*index) } = Entity(0);
-            table.free_index(*index);
+        let (table_id, index) = self.entity_location(entity)?;
+        {
+            self.free_row(table_id, index);
+            self.remove_sparse_components(entity);
 
-            *unsafe {
+            let meta = unsafe {
                 self.inner()
                     .entities
                     .get_mut(*entity as usize)
                     .unwrap_unchecked()
-            } = None;
+            };
+            meta.generation = meta.generation.wrapping_add(1);
+            meta.location = None;
 
-            self.inner().free_entities.insert(entity);
+            self.inner().free_entities.insert(entity.index);
         }
+        Ok(())
     }
 
-    pub fn has_component<T: Component + 'static>(&self, entity: Entity) -> bool {
-        if let Some(Some((archetype, _))) = self.inner().entities.get(*entity as usize) {
-            unsafe {
-                self.inner()
-                    .tables
-                    .get(archetype)
-                    .unwrap_unchecked()
-                    .has_component::<T>()
+    /// Drops the components in the row, or moves them to their removal log, and removes it from
+    /// the table
+    fn free_row(&self, table_id: TableId, index: usize) {
+        let table = self.table(table_id);
+        let entity = unsafe {
+            *table
+                .get_column::<Entity>()
+                .unwrap_unchecked()
+                .read::<Entity>(index)
+        };
+        let mut removed = self.inner().removed.lock().unwrap();
+        for (id, col) in table.columns_mut() {
+            match removed.get_mut(id.0 as usize).and_then(Option::as_mut) {
+                Some(log) => unsafe { log.push(entity, col, index) },
+                None => unsafe { col.drop_item(index) },
             }
-        } else {
-            false
         }
+        drop(removed);
+        self.remove_row(table_id, index);
     }
 
-    pub fn component<T: Component + 'static>(&self, entity: Entity) -> Option<&T> {
-        unsafe {
-            if let Some(Some((archetype, index))) = self.inner().entities.get(*entity as usize) {
-                self.inner()
-                    .tables
-                    .get(archetype)
-                    .unwrap_unchecked()
-                    .try_read::<T>(*index)
-            } else {
-                None
+    /// Swap-removes a row whose items were dropped or moved out, and patches the location of the
+    /// entity that took its place
+    fn remove_row(&self, table_id: TableId, index: usize) {
+        if let Some(moved) = unsafe { self.table(table_id).swap_remove(index) } {
+            if let Some(location) = self.inner().entities[*moved as usize].location.as_mut() {
+                location.1 = index;
             }
         }
     }
 
-    #[allow(clippy::mut_from_ref)]
-    pub fn component_mut<T: Component + 'static>(&self, entity: Entity) -> Option<&mut T> {
-        unsafe {
-            if let Some(Some((archetype, index))) = self.inner().entities.get(*entity as usize) {
-                self.inner()
-                    .tables
-                    .get_mut(archetype)
-                    .unwrap_unchecked()
-                    .try_read_mut::<T>(*index)
-            } else {
-                None
-            }
+    /// Frees the row of `entity` if it has one, for inserts that replace what it holds
+    fn clear_slot(&self, entity: Entity) {
+        if let Some((table_id, index)) = self.location(entity) {
+            self.free_row(table_id, index);
+            self.remove_sparse_components(entity);
+        }
+    }
+
+    pub fn has_component<T: Component + 'static>(&self, entity: Entity) -> bool {
+        match self.location(entity) {
+            Some((table_id, index)) => unsafe {
+                self.fetch::<T>(self.table(table_id))
+                    .read::<T>(entity, index)
+                    .is_some()
+            },
+            None => false,
+        }
+    }
+
+    /// # Panics
+    ///
+    /// If the component is mutably borrowed by a running system or a `RefMut`
+    pub fn component<T: Component + 'static>(&self, entity: Entity) -> Option<Ref<'_, T>> {
+        let (table_id, index) = self.location(entity)?;
+        let value = unsafe {
+            self.fetch::<T>(self.table(table_id))
+                .read::<T>(entity, index)?
+        };
+
+        let id = T::metadata_static().id();
+        if let Err(err) = self.inner().borrows.acquire_read(id.into()) {
+            panic!("{}", err);
+        }
+        Some(Ref::new(value, self, id.into()))
+    }
+
+    /// # Panics
+    ///
+    /// If the component is borrowed by a running system or a `Ref`/`RefMut`
+    pub fn component_mut<T: Component + 'static>(&self, entity: Entity) -> Option<RefMut<'_, T>> {
+        let (table_id, index) = self.location(entity)?;
+        let fetch = self.fetch::<T>(self.table(table_id));
+        unsafe { fetch.read::<T>(entity, index)? };
+
+        // Only marks the component as changed once it is actually borrowed
+        let id = T::metadata_static().id();
+        if let Err(err) = self.inner().borrows.acquire_write(id.into()) {
+            panic!("{}", err);
         }
+        let value = unsafe {
+            fetch
+                .read_mut::<T>(entity, index, self.change_tick())
+                .unwrap_unchecked()
+        };
+        Some(RefMut::new(value, self, id.into()))
     }
 
-    pub fn add_component<T: Component + 'static>(&self, entity: Entity, component: T) {
-        if self
+    /// Adds a global value, replacing and returning the one of the same type if there is one
+    ///
+    /// # Panics
+    ///
+    /// While a system runs or a component or resource is borrowed, since running systems look
+    /// resources up without locking
+    pub fn insert_resource<R: Send + Sync + 'static>(&self, value: R) -> Option<R> {
+        if self.is_deferring() {
+            panic!("{}", EcsError::Deferred);
+        }
+
+        let id = resource_id::<R>();
+        let resources = &mut self.inner().resources;
+        if id.0 as usize >= resources.len() {
+            resources.resize_with(id.0 as usize + 1, || None);
+        }
+        let old = resources[id.0 as usize].replace(ResourceData {
+            value: Box::new(value),
+            ticks: Cell::new(ComponentTicks::new(self.change_tick())),
+        });
+        old.map(|old| *old.value.downcast::<R>().unwrap())
+    }
+
+    /// # Panics
+    ///
+    /// Same as [`World::insert_resource`]
+    pub fn remove_resource<R: Send + Sync + 'static>(&self) -> Option<R> {
+        if self.is_deferring() {
+            panic!("{}", EcsError::Deferred);
+        }
+
+        let old = self
             .inner()
-            .num_systems_running
-            .load(std::sync::atomic::Ordering::Relaxed)
-            == 0
-        {
-            let _ = self._add_component(entity, T::metadata_static(), &component);
+            .resources
+            .get_mut(resource_id::<R>().0 as usize)
+            .and_then(Option::take);
+        old.map(|old| *old.value.downcast::<R>().unwrap())
+    }
+
+    pub fn has_resource<R: Send + Sync + 'static>(&self) -> bool {
+        self.resource_ptr::<R>().is_some()
+    }
+
+    /// # Panics
+    ///
+    /// If the resource is mutably borrowed by a running system or a `RefMut`
+    pub fn resource<R: Send + Sync + 'static>(&self) -> Option<Ref<'_, R>> {
+        let value = unsafe { &*self.resource_ptr::<R>()? };
+        let id = resource_id::<R>();
+        if let Err(err) = self.inner().borrows.acquire_read(id.into()) {
+            panic!("{}", err);
+        }
+        Some(Ref::new(value, self, id.into()))
+    }
+
+    /// # Panics
+    ///
+    /// If the resource is borrowed by a running system or a `Ref`/`RefMut`
+    pub fn resource_mut<R: Send + Sync + 'static>(&self) -> Option<RefMut<'_, R>> {
+        let (value, ticks) = self.resource_entry::<R>()?;
+        let id = resource_id::<R>();
+        if let Err(err) = self.inner().borrows.acquire_write(id.into()) {
+            panic!("{}", err);
+        }
+
+        // Only marks the resource as changed once it is actually borrowed
+        ticks.set(ComponentTicks {
+            changed: self.change_tick(),
+            ..ticks.get()
+        });
+        Some(RefMut::new(unsafe { &mut *value }, self, id.into()))
+    }
+
+    /// Whether the resource was inserted or borrowed mutably since `last_run`, which is then
+    /// moved to now. False if there is no such resource. See
+    /// [`schedule::resource_changed`](crate::schedule::resource_changed) to use it as a run
+    /// condition.
+    pub fn is_resource_changed<R: Send + Sync + 'static>(&self, last_run: &mut LastRun) -> bool {
+        let ticks = self.advance_tick(last_run.0);
+        last_run.0 = ticks.this_run;
+        self.resource_entry::<R>()
+            .is_some_and(|(_, resource_ticks)| ticks.is_newer(resource_ticks.get().changed))
+    }
+
+    fn resource_ptr<R: Send + Sync + 'static>(&self) -> Option<*mut R> {
+        self.resource_entry::<R>().map(|(value, _)| value)
+    }
+
+    /// The boxes of the resources never move, so the pointer stays valid until the resource is
+    /// replaced or removed
+    fn resource_entry<R: Send + Sync + 'static>(&self) -> Option<(*mut R, &Cell<ComponentTicks>)> {
+        let data = self
+            .inner()
+            .resources
+            .get_mut(resource_id::<R>().0 as usize)?
+            .as_mut()?;
+        let value = data.value.downcast_mut::<R>()? as *mut R;
+        Some((value, &data.ticks))
+    }
+
+    pub(crate) fn release_read(&self, key: BorrowKey) {
+        self.inner().borrows.release_read(key);
+        self.apply_commands_if_idle();
+    }
+
+    pub(crate) fn release_write(&self, key: BorrowKey) {
+        self.inner().borrows.release_write(key);
+        self.apply_commands_if_idle();
+    }
+
+    /// Adds the component to the entity, or defers it while a system runs or a component is
+    /// borrowed, in which case a failure is reported to the handler set with
+    /// [`World::on_command_error`] instead
+    ///
+    /// # Panics
+    ///
+    /// If allocating storage for the component fails, see [`World::try_add_component`]
+    pub fn add_component<T: Component + 'static>(
+        &self,
+        entity: Entity,
+        component: T,
+    ) -> Result<(), EcsError> {
+        self.try_add_component(entity, component)
+            .map_err(panic_on_alloc)
+    }
+
+    /// Same as [`World::add_component`], but returns [`EcsError::CapacityExceeded`] instead of
+    /// panicking if storage for the component cannot be allocated. The entity is left unchanged
+    /// in that case.
+    pub fn try_add_component<T: Component + 'static>(
+        &self,
+        entity: Entity,
+        component: T,
+    ) -> Result<(), EcsError> {
+        if !self.is_deferring() {
+            self._add_component(entity, T::metadata_static(), &component)?;
+            mem::forget(component);
         } else {
-            self.inner().cmd_queue.push(Cmd::AddComponent((
-                entity,
-                T::metadata_static(),
-                Box::new(component),
-            )));
+            self.commands().add_component(entity, component);
         }
+        Ok(())
     }
 
+    /// Adds every component of the bundle the entity does not have yet
+    ///
+    /// # Panics
+    ///
+    /// If allocating storage for the components fails
+    pub fn add_bundle<B: Bundle>(&self, entity: Entity, bundle: B) -> Result<(), EcsError> {
+        if !self.is_deferring() {
+            self._add_bundle(entity, bundle.into_boxes())
+                .map_err(panic_on_alloc)
+        } else {
+            self.commands().add_bundle(entity, bundle);
+            Ok(())
+        }
+    }
+
+    fn _add_bundle(
+        &self,
+        entity: Entity,
+        components: Vec<Box<dyn Component>>,
+    ) -> Result<(), EcsError> {
+        self.entity_location(entity)?;
+        for component in components {
+            match self._add_component(entity, component.metadata(), component.as_ref()) {
+                Ok(()) => free_box(component),
+                Err(EcsError::AlreadyHasComponent(..)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves the component in. On failure the caller still owns it.
     fn _add_component(
         &self,
         entity: Entity,
         metadata: Metadata,
         component: &dyn Component,
-    ) -> Result<(), ()> {
-        if let Some(Some((archetype, index))) = self.inner().entities.get_mut(*entity as usize) {
-            let mut new_archetype = *archetype;
-            new_archetype.set(component.metadata());
+    ) -> Result<(), EcsError> {
+        let (table_id, index) = self.entity_location(entity)?;
 
-            if *archetype == new_archetype {
-                return Result::Err(());
+        if metadata.is_sparse() {
+            let set = self.sparse_set_or_insert(metadata);
+            if set.contains(entity) {
+                return Err(EcsError::AlreadyHasComponent(entity, metadata.id()));
             }
+            set.try_reserve(1, *entity as usize)?;
+            unsafe { set.insert_any(entity, component, self.change_tick()) };
+            return Ok(());
+        }
 
-            if let std::collections::hash_map::Entry::Vacant(e) =
-                self.inner().tables.entry(new_archetype)
-            {
-                e.insert(Table::new());
-
-                let table = unsafe { self.inner().tables.get_mut(archetype).unwrap_unchecked() };
-                let new_table = unsafe {
-                    self.inner()
-                        .tables
-                        .get_mut(&new_archetype)
-                        .unwrap_unchecked()
-                };
-
-                for id in 0..128 {
-                    unsafe {
-                        if let Some(col) = table.get_column_by_id(ComponentId(id as u32)) {
-                            if new_archetype.contains_id(id) {
-                                new_table.add_column_by_id(
-                                    ComponentId(id as u32),
-                                    col.get_component_size(),
-                                    metadata.align(),
-                                )
-                            }
-                        }
-                    };
-                }
-            }
+        let Some(edge) = self.add_edge(table_id, metadata) else {
+            return Err(EcsError::AlreadyHasComponent(entity, metadata.id()));
+        };
+        self.table(edge.target).try_reserve(&[metadata], 1)?;
 
-            let table = unsafe { self.inner().tables.get_mut(archetype).unwrap_unchecked() };
-            let new_table = unsafe {
-                self.inner()
-                    .tables
-                    .get_mut(&new_archetype)
-                    .unwrap_unchecked()
-            };
-            let new_index = new_table.reserve_index();
-
-            for id in 0..128 {
-                if archetype.contains_id(id) {
-                    unsafe {
-                        Column::copy_item_from_column(
-                            table
-                                .get_column_by_id_mut(ComponentId(id as u32))
-                                .unwrap_unchecked(),
-                            new_table
-                                .get_column_by_id_mut(ComponentId(id as u32))
-                                .unwrap_unchecked(),
-                            *index,
-                            new_index,
-                        );
-                    }
-                }
-            }
-
-            unsafe { table.write::<Entity>(*index, Entity(0)) };
-            table.free_index(*index);
+        let new_index = self.migrate_row(table_id, index, &edge);
+        unsafe {
+            self.table(edge.target)
+                .write_any(metadata, new_index, component, self.change_tick())
+        };
 
-            unsafe { new_table.write_any(metadata, new_index, component) };
+        self.set_location(entity, (edge.target, new_index));
+        self.remove_row(table_id, index);
 
-            *archetype = new_archetype;
-            *index = new_index;
+        Ok(())
+    }
 
-            return Result::Ok(());
+    /// Drops the component and removes it from the entity, or defers it while a system runs or a
+    /// component is borrowed, in which case a failure is reported to the handler set with
+    /// [`World::on_command_error`] instead
+    pub fn remove_component<T: Component + 'static>(&self, entity: Entity) -> Result<(), EcsError> {
+        if !self.is_deferring() {
+            self._remove_component(entity, T::metadata_static())
+        } else {
+            self.commands().remove_component::<T>(entity);
+            Ok(())
         }
-
-        Result::Err(())
     }
 
-    pub fn remove_component<T: Component + 'static>(&self, entity: Entity) {
-        if self
-            .inner()
-            .num_systems_running
-            .load(std::sync::atomic::Ordering::Relaxed)
-            == 0
-        {
-            let _ = self._remove_component(entity, T::metadata_static());
+    /// Removes every component of the bundle the entity has
+    pub fn remove_bundle<B: Bundle>(&self, entity: Entity) -> Result<(), EcsError> {
+        if !self.is_deferring() {
+            self._remove_bundle(entity, B::bundle_metadata())
         } else {
-            self.inner()
-                .cmd_queue
-                .push(Cmd::RemoveComponent((entity, T::metadata_static())));
+            self.commands().remove_bundle::<B>(entity);
+            Ok(())
         }
     }
 
-    fn _remove_component(&self, entity: Entity, metadata: Metadata) -> Result<(), ()> {
-        if let Some(Some((archetype, index))) = self.inner().entities.get_mut(*entity as usize) {
-            let mut new_archetype = *archetype;
-            new_archetype.unset(metadata);
-
-            if *archetype == new_archetype {
-                return Result::Err(());
+    fn _remove_bundle(&self, entity: Entity, metadata: Vec<Metadata>) -> Result<(), EcsError> {
+        self.entity_location(entity)?;
+        for metadata in metadata {
+            match self._remove_component(entity, metadata) {
+                Ok(()) | Err(EcsError::MissingComponent(..)) => {}
+                Err(err) => return Err(err),
             }
+        }
+        Ok(())
+    }
 
-            if !self.inner().tables.contains_key(&new_archetype) {
-                if self
-                    .inner()
-                    .tables
-                    .insert(new_archetype, Table::new())
-                    .is_some()
-                {
-                    panic!()
-                }
+    fn _remove_component(&self, entity: Entity, metadata: Metadata) -> Result<(), EcsError> {
+        let (table_id, index) = self.entity_location(entity)?;
 
-                let table = unsafe { self.inner().tables.get_mut(archetype).unwrap_unchecked() };
-                let new_table = unsafe {
-                    self.inner()
-                        .tables
-                        .get_mut(&new_archetype)
-                        .unwrap_unchecked()
-                };
-
-                for id in 0..128usize {
-                    unsafe {
-                        if let Some(col) = table.get_column_by_id(ComponentId(id as u32)) {
-                            if new_archetype.contains_id(id) {
-                                new_table.add_column_by_id(
-                                    ComponentId(id as u32),
-                                    col.get_component_size(),
-                                    metadata.align(),
-                                )
-                            }
-                        }
-                    };
-                }
-            }
+        let mut removed = self.inner().removed.lock().unwrap();
+        let log = removed
+            .get_mut(metadata.id().0 as usize)
+            .and_then(Option::as_mut);
 
-            let table = unsafe { self.inner().tables.get_mut(archetype).unwrap_unchecked() };
-            let new_table = unsafe {
-                self.inner()
-                    .tables
-                    .get_mut(&new_archetype)
-                    .unwrap_unchecked()
+        if metadata.is_sparse() {
+            let removed = self
+                .sparse_set(metadata.id())
+                .is_some_and(|set| set.remove_into(entity, log));
+            return if removed {
+                Ok(())
+            } else {
+                Err(EcsError::MissingComponent(entity, metadata.id()))
             };
-            let new_index = new_table.reserve_index();
-
-            for id in 0..128usize {
-                if new_archetype.contains_id(id) {
-                    unsafe {
-                        Column::copy_item_from_column(
-                            table
-                                .get_column_by_id_mut(ComponentId(id as u32))
-                                .unwrap_unchecked(),
-                            new_table
-                                .get_column_by_id_mut(ComponentId(id as u32))
-                                .unwrap_unchecked(),
-                            *index,
-                            new_index,
-                        );
-                    }
-                }
+        }
+
+        let Some(edge) = self.remove_edge(table_id, metadata) else {
+            return Err(EcsError::MissingComponent(entity, metadata.id()));
+        };
+
+        unsafe {
+            let col = self
+                .table(table_id)
+                .get_column_by_id_mut(metadata.id())
+                .unwrap_unchecked();
+            match log {
+                Some(log) => log.push(entity, col, index),
+                None => col.drop_item(index),
             }
+        };
+        drop(removed);
+        let new_index = self.migrate_row(table_id, index, &edge);
 
-            unsafe { table.write::<Entity>(*index, Entity(0)) };
-            table.free_index(*index);
+        self.set_location(entity, (edge.target, new_index));
+        self.remove_row(table_id, index);
 
-            *archetype = new_archetype;
-            *index = new_index;
+        Ok(())
+    }
 
-            return Result::Ok(());
-        }
+    /// Starts logging the entities that lose `T`, through [`World::remove_component`],
+    /// [`World::despawn`] or by being replaced with [`World::insert`]. The log is drained with
+    /// [`World::removed`] or a [`RemovedComponents`] parameter.
+    pub fn track_removed<T: Component + 'static>(&self) {
+        self.removed_log(T::metadata_static(), |_| {});
+    }
 
-        Result::Err(())
+    /// Same as [`World::track_removed`], but the removed values are moved into the log instead of
+    /// being dropped, until it is drained
+    pub fn keep_removed_values<T: Component + 'static>(&self) {
+        self.removed_log(T::metadata_static(), |log| {
+            log.keep_values(T::metadata_static())
+        });
     }
 
-    pub fn destroy_component<T: Component + 'static>(&self, entity: Entity) {
-        unsafe {
-            if let Some(Some((archetype, index))) = self.inner().entities.get(*entity as usize) {
-                self.inner()
-                    .tables
-                    .get_mut(archetype)
-                    .unwrap_unchecked()
-                    .destroy::<T>(*index)
-            }
+    /// Drains the log of the entities that lost `T` since the last call, starting it if needed.
+    /// The values are only there if [`World::keep_removed_values`] was called.
+    pub fn removed<T: Component + 'static>(&self) -> Vec<(Entity, Option<T>)> {
+        self.removed_log(T::metadata_static(), |log| unsafe { log.drain::<T>() })
+    }
+
+    fn removed_log<R>(&self, metadata: Metadata, f: impl FnOnce(&mut RemovedLog) -> R) -> R {
+        let mut removed = self.inner().removed.lock().unwrap();
+        let id = metadata.id().0 as usize;
+        if id >= removed.len() {
+            removed.resize_with(id + 1, || None);
         }
+        f(removed[id].get_or_insert_with(RemovedLog::new))
+    }
+
+    /// Drops the component's value and removes it from the entity
+    pub fn destroy_component<T: Component + 'static>(
+        &self,
+        entity: Entity,
+    ) -> Result<(), EcsError> {
+        self.remove_component::<T>(entity)
     }
 
     fn increment_num_running_systems(&self) -> usize {
@@ -964,57 +1886,184 @@ impl World {
         }
     }
 
-    /// # SAFETY
+    /// Runs `f` once for every entity matching its parameters. Structural changes made while it
+    /// runs are deferred until the outermost `run` returns.
     ///
-    /// - Nesting queries with a subset relationship could result in mutable aliasing
-    /// ```
+    /// `f` counts as a system that never ran, so [`Added`] and [`Changed`] match every entity.
+    /// Use [`World::run_with`] to only match what happened since its previous run.
+    ///
+    /// # Panics
+    ///
+    /// If the components `f` accesses conflict with each other or with the borrows of a system
+    /// that is already running, e.g. when nesting queries
+    /// ```ignore
     ///   world.run(|foo: &mut Foo, bar: &Bar| {
-    ///      world.run(|foo: &Foo /* UB */| {
+    ///      world.run(|foo: &Foo /* panics */| {
     ///          ...
     ///      });
     ///   });
     /// ```
-    pub unsafe fn run<'a, Params>(&'a self, mut f: impl System<'a, Params>) {
+    pub fn run<'a, Params>(&'a self, f: impl System<'a, Params>) {
+        if let Err(err) = self.try_run(f) {
+            panic!("{}", err);
+        }
+    }
+
+    /// Same as [`World::run`], with [`Added`] and [`Changed`] matching what happened since
+    /// `last_run`, which is then moved to this run
+    pub fn run_with<'a, Params>(&'a self, last_run: &mut LastRun, f: impl System<'a, Params>) {
+        if let Err(err) = self.try_run_with(last_run, f) {
+            panic!("{}", err);
+        }
+    }
+
+    /// Returns the entities matching `Q`, e.g.
+    /// ```ignore
+    ///   let mut query = world.query::<(&Position, &mut Velocity, Without<Frozen>)>();
+    ///   for (pos, vel, _) in query.iter_mut() {
+    ///       ...
+    ///   }
+    /// ```
+    /// The components `Q` accesses stay borrowed until the query is dropped. Like with
+    /// [`World::run`], [`Added`] and [`Changed`] match every entity, see [`World::query_with`].
+    ///
+    /// # Panics
+    ///
+    /// If the components `Q` accesses conflict with each other or with outstanding borrows
+    pub fn query<Q: QueryData>(&self) -> Query<'_, Q> {
+        self.try_query().unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Same as [`World::query`] but returns an error instead of panicking on conflicting borrows
+    pub fn try_query<Q: QueryData>(&self) -> Result<Query<'_, Q>, BorrowError> {
+        self.try_query_with(&mut LastRun::new())
+    }
+
+    /// Same as [`World::query`], with [`Added`] and [`Changed`] matching what happened since
+    /// `last_run`, which is then moved to this query
+    pub fn query_with<Q: QueryData>(&self, last_run: &mut LastRun) -> Query<'_, Q> {
+        self.try_query_with(last_run)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    pub fn try_query_with<Q: QueryData>(
+        &self,
+        last_run: &mut LastRun,
+    ) -> Result<Query<'_, Q>, BorrowError> {
+        let mut access = Access::default();
+        Q::component_access(&mut access);
+        self.inner().borrows.acquire(&access)?;
+        let ticks = self.advance_tick(last_run.0);
+        last_run.0 = ticks.this_run;
+        Ok(Query::new(self, access, ticks))
+    }
+
+    pub(crate) fn release(&self, access: &Access) {
+        self.inner().borrows.release(access);
+        self.apply_commands_if_idle();
+    }
+
+    /// Same as [`World::run`] but returns an error instead of panicking on conflicting borrows
+    pub fn try_run<'a, Params>(&'a self, f: impl System<'a, Params>) -> Result<(), BorrowError> {
+        self.try_run_with(&mut LastRun::new(), f)
+    }
+
+    pub fn try_run_with<'a, Params>(
+        &'a self,
+        last_run: &mut LastRun,
+        mut f: impl System<'a, Params>,
+    ) -> Result<(), BorrowError> {
+        let mut access = Access::default();
+        f.access(&mut access);
+        self.inner().borrows.acquire(&access)?;
+
+        struct Running<'w>(&'w World, Access);
+        impl Drop for Running<'_> {
+            fn drop(&mut self) {
+                self.0.decrement_num_running_systems();
+                self.0.inner().borrows.release(&self.1);
+            }
+        }
+
+        let ticks = self.advance_tick(last_run.0);
+        last_run.0 = ticks.this_run;
         self.increment_num_running_systems();
-        f.run(self);
-        let num_running_systems = self.decrement_num_running_systems();
-
-        if num_running_systems == 0 {
-            for cmd in &self.inner().cmd_queue {
-                match cmd {
-                    Cmd::AddComponent((ent, metadata, component)) => {
-                        let _ = self._add_component(*ent, *metadata, component.as_ref());
-                    }
-                    Cmd::RemoveComponent((ent, metadata)) => {
-                        let _ = self._remove_component(*ent, *metadata);
-                    }
-                };
+        let running = Running(self, access);
+        unsafe { f.run(self, ticks) };
+        drop(running);
+
+        self.apply_commands_if_idle();
+
+        Ok(())
+    }
+
+    /// Sets the function that is called with the error of every deferred command that fails when
+    /// it is applied. Without one such failures are ignored. The handler must not call
+    /// `on_command_error` itself.
+    pub fn on_command_error(&self, handler: impl FnMut(EcsError) + Send + 'static) {
+        *self.inner().command_error_handler.lock().unwrap() = Some(Box::new(handler));
+    }
+
+    /// Applies the structural changes deferred by systems and [`Commands`]. Does nothing while a
+    /// system runs or a component is borrowed, the changes are then applied once that ends.
+    pub fn flush(&self) {
+        self.apply_commands_if_idle();
+    }
+
+    fn apply_commands_if_idle(&self) {
+        if self.is_deferring() {
+            return;
+        }
+
+        self.materialize_reserved_entities();
+
+        let cmds = mem::take(&mut *self.inner().cmd_queue.lock().unwrap());
+        for cmd in cmds {
+            let result = match cmd {
+                Cmd::AddComponent((ent, metadata, component)) => self
+                    ._add_component(ent, metadata, component.as_ref())
+                    .map(|()| free_box(component)),
+                Cmd::RemoveComponent((ent, metadata)) => self._remove_component(ent, metadata),
+                Cmd::Despawn(ent) => self.despawn(ent),
+                Cmd::Insert((ent, components)) => {
+                    self.insert_from_slice_of_boxes(ent, components).map(|_| ())
+                }
+                Cmd::AddBundle((ent, components)) => self._add_bundle(ent, components),
+                Cmd::RemoveBundle((ent, metadata)) => self._remove_bundle(ent, metadata),
+            };
+
+            if let Err(err) = result {
+                if let Some(handler) = self.inner().command_error_handler.lock().unwrap().as_mut() {
+                    handler(err);
+                }
             }
-            self.inner().cmd_queue.clear();
         }
     }
 
-    /// This could return a deleted entity so do not unwrap on ::component<..>(entity)
+    /// Calls `f` for every entity with exactly this archetype. Structural changes made by `f` are
+    /// deferred until the iteration is done.
     pub fn for_each_with_archetype(&self, archetype: Archetype, mut f: impl FnMut(Entity)) {
+        let _deferring = self.defer();
         unsafe {
-            for (table_archetype, table) in self.inner().tables.iter_mut() {
-                if archetype == *table_archetype {
-                    let len = table.len();
-                    let entities = table.get_column::<Entity>().unwrap_unchecked();
-                    for i in 0..len {
-                        f(*entities.read(i))
-                    }
+            if let Some(table_id) = self.inner().table_ids.get(&archetype) {
+                let table = self.table(*table_id);
+                let len = table.len();
+                let entities = table.get_column::<Entity>().unwrap_unchecked();
+                for i in 0..len {
+                    f(*entities.read(i))
                 }
             }
         }
     }
 
     // TODO could be named better no?
-    /// This could return a deleted entity so do not unwrap on ::component<..>(entity)
+    /// Calls `f` for every entity whose archetype contains this one. Structural changes made by
+    /// `f` are deferred until the iteration is done.
     pub fn for_each_with_archetype_subset(&self, archetype: Archetype, mut f: impl FnMut(Entity)) {
+        let _deferring = self.defer();
         unsafe {
-            for (table_archetype, table) in self.inner().tables.iter_mut() {
-                if archetype.subset_of(*table_archetype) {
+            for table in self.inner().tables.iter() {
+                if archetype.subset_of(table.archetype()) {
                     let len = table.len();
                     let entities = table.get_column::<Entity>().unwrap_unchecked();
                     for i in 0..len {
@@ -1025,13 +2074,37 @@ impl World {
         }
     }
 
+    /// Counts as a running system until the guard is dropped, which then applies the deferred
+    /// changes. The guard is dropped on panics too, so that changes are not deferred forever.
+    pub(crate) fn defer(&self) -> Deferring<'_> {
+        self.increment_num_running_systems();
+        Deferring(self)
+    }
+
     pub fn num_entities_max(&self) -> u32 {
         self.inner().entities.len() as u32
     }
 }
 
+pub(crate) struct Deferring<'w>(&'w World);
+
+impl Drop for Deferring<'_> {
+    fn drop(&mut self) {
+        self.0.decrement_num_running_systems();
+        self.0.apply_commands_if_idle();
+    }
+}
+
 impl Default for World {
     fn default() -> Self {
         Self::new()
     }
 }
+
+impl Drop for World {
+    fn drop(&mut self) {
+        if self.owned {
+            drop(unsafe { Box::from_raw(self.inner) });
+        }
+    }
+}
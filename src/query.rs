@@ -0,0 +1,1052 @@
+use std::{
+    any::type_name,
+    marker::PhantomData,
+    mem,
+    num::NonZeroUsize,
+    ops::Range,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use crate::{
+    archetype::Archetype,
+    archetype_may_contain,
+    borrow::Access,
+    change::SystemTicks,
+    commands::{ParCommands, SendCmds},
+    component::Component,
+    table::{Column, Table, TableId},
+    Added, Changed, ComponentState, Entity, Fetch, With, Without, World,
+};
+
+/// What a [`Query`] fetches for every matching entity: `&T`, `&mut T`, `Option<&T>`,
+/// `Option<&mut T>`, `Has<T>`, `AnyOf<(..)>`, the `With<T>`/`Without<T>`/`Added<T>`/`Changed<T>`
+/// and `Or<(..)>` filters, or a tuple of those. Systems take the same items as parameters.
+///
+/// # Safety
+///
+/// `component_access` must declare every component the items read or write
+pub unsafe trait QueryData {
+    type Item<'w>;
+    /// The item with every `&mut T` turned into `&T`
+    type ReadOnlyItem<'w>;
+    /// The items of all the rows of a table as slices, see [`Query::chunks_mut`]
+    type Chunk<'w>;
+    type ReadOnlyChunk<'w>;
+    #[doc(hidden)]
+    type State<'w>;
+    /// `Self` with `'static` lifetimes, which keys the tables cached for the query
+    #[doc(hidden)]
+    type Key: 'static;
+
+    fn component_access(access: &mut Access);
+    fn match_archetype(archetype: &Archetype) -> bool;
+
+    #[doc(hidden)]
+    #[allow(private_interfaces)]
+    fn state<'w>(world: &'w World, table: &'w Table, ticks: SystemTicks) -> Self::State<'w>;
+
+    /// Checks what the archetype cannot tell: sparse components and change ticks
+    #[doc(hidden)]
+    fn match_row(state: &Self::State<'_>, entity: Entity, index: usize) -> bool;
+
+    /// # Safety
+    ///
+    /// The row must match and no other item of the row may be alive
+    #[doc(hidden)]
+    unsafe fn item<'w>(state: &Self::State<'w>, entity: Entity, index: usize) -> Self::Item<'w>;
+
+    /// # Safety
+    ///
+    /// The row must match and no mutable item of the row may be alive
+    #[doc(hidden)]
+    unsafe fn read_only_item<'w>(
+        state: &Self::State<'w>,
+        entity: Entity,
+        index: usize,
+    ) -> Self::ReadOnlyItem<'w>;
+
+    /// Whether the rows of a matching table all match, which chunks rely on. Sparse components
+    /// and change filters are checked per row so they cannot be chunked.
+    fn is_dense() -> bool;
+
+    /// # Safety
+    ///
+    /// `len` must be the length of the table, which must match and be dense, and no other chunk
+    /// or item of the table may be alive
+    #[doc(hidden)]
+    unsafe fn chunk<'w>(state: &Self::State<'w>, len: usize) -> Self::Chunk<'w>;
+
+    /// # Safety
+    ///
+    /// Same as `chunk`, but only mutable chunks or items may not be alive
+    #[doc(hidden)]
+    unsafe fn read_only_chunk<'w>(state: &Self::State<'w>, len: usize) -> Self::ReadOnlyChunk<'w>;
+}
+
+unsafe impl<T: Component + 'static> QueryData for &T {
+    type Key = &'static T;
+    type Item<'w> = &'w T;
+    type ReadOnlyItem<'w> = &'w T;
+    type State<'w> = ComponentState<'w>;
+    type Chunk<'w> = &'w [T];
+    type ReadOnlyChunk<'w> = &'w [T];
+
+    fn component_access(access: &mut Access) {
+        access.add_read(T::metadata_static().id());
+    }
+
+    fn match_archetype(archetype: &Archetype) -> bool {
+        archetype_may_contain(archetype, T::metadata_static())
+    }
+
+    #[allow(private_interfaces)]
+    fn state<'w>(world: &'w World, table: &'w Table, ticks: SystemTicks) -> ComponentState<'w> {
+        ComponentState::new::<T>(world, table, ticks)
+    }
+
+    #[inline(always)]
+    fn match_row(state: &ComponentState<'_>, entity: Entity, _: usize) -> bool {
+        state.0.contains(entity)
+    }
+
+    #[inline(always)]
+    unsafe fn item<'w>(state: &Self::State<'w>, entity: Entity, index: usize) -> &'w T {
+        state.0.read::<T>(entity, index).unwrap_unchecked()
+    }
+
+    #[inline(always)]
+    unsafe fn read_only_item<'w>(state: &Self::State<'w>, entity: Entity, index: usize) -> &'w T {
+        state.0.read::<T>(entity, index).unwrap_unchecked()
+    }
+
+    fn is_dense() -> bool {
+        !T::metadata_static().is_sparse()
+    }
+
+    #[inline(always)]
+    unsafe fn chunk<'w>(state: &Self::State<'w>, len: usize) -> Self::Chunk<'w> {
+        state.0.slice::<T>(len).unwrap_unchecked()
+    }
+
+    #[inline(always)]
+    unsafe fn read_only_chunk<'w>(state: &Self::State<'w>, len: usize) -> Self::ReadOnlyChunk<'w> {
+        state.0.slice::<T>(len).unwrap_unchecked()
+    }
+}
+
+unsafe impl<T: Component + 'static> QueryData for &mut T {
+    type Key = &'static mut T;
+    type Item<'w> = &'w mut T;
+    type ReadOnlyItem<'w> = &'w T;
+    type State<'w> = ComponentState<'w>;
+    type Chunk<'w> = &'w mut [T];
+    type ReadOnlyChunk<'w> = &'w [T];
+
+    fn component_access(access: &mut Access) {
+        access.add_write(T::metadata_static().id());
+    }
+
+    fn match_archetype(archetype: &Archetype) -> bool {
+        archetype_may_contain(archetype, T::metadata_static())
+    }
+
+    #[allow(private_interfaces)]
+    fn state<'w>(world: &'w World, table: &'w Table, ticks: SystemTicks) -> ComponentState<'w> {
+        ComponentState::new::<T>(world, table, ticks)
+    }
+
+    #[inline(always)]
+    fn match_row(state: &ComponentState<'_>, entity: Entity, _: usize) -> bool {
+        state.0.contains(entity)
+    }
+
+    #[inline(always)]
+    unsafe fn item<'w>(state: &Self::State<'w>, entity: Entity, index: usize) -> &'w mut T {
+        state
+            .0
+            .read_mut::<T>(entity, index, state.1.this_run)
+            .unwrap_unchecked()
+    }
+
+    #[inline(always)]
+    unsafe fn read_only_item<'w>(state: &Self::State<'w>, entity: Entity, index: usize) -> &'w T {
+        state.0.read::<T>(entity, index).unwrap_unchecked()
+    }
+
+    fn is_dense() -> bool {
+        !T::metadata_static().is_sparse()
+    }
+
+    #[inline(always)]
+    unsafe fn chunk<'w>(state: &Self::State<'w>, len: usize) -> Self::Chunk<'w> {
+        state
+            .0
+            .slice_mut::<T>(len, state.1.this_run)
+            .unwrap_unchecked()
+    }
+
+    #[inline(always)]
+    unsafe fn read_only_chunk<'w>(state: &Self::State<'w>, len: usize) -> Self::ReadOnlyChunk<'w> {
+        state.0.slice::<T>(len).unwrap_unchecked()
+    }
+}
+
+unsafe impl<T: Component + 'static> QueryData for Option<&T> {
+    type Key = Option<&'static T>;
+    type Item<'w> = Option<&'w T>;
+    type ReadOnlyItem<'w> = Option<&'w T>;
+    type State<'w> = ComponentState<'w>;
+    type Chunk<'w> = Option<&'w [T]>;
+    type ReadOnlyChunk<'w> = Option<&'w [T]>;
+
+    fn component_access(access: &mut Access) {
+        access.add_read(T::metadata_static().id());
+    }
+
+    fn match_archetype(_: &Archetype) -> bool {
+        true
+    }
+
+    #[allow(private_interfaces)]
+    fn state<'w>(world: &'w World, table: &'w Table, ticks: SystemTicks) -> ComponentState<'w> {
+        ComponentState::new::<T>(world, table, ticks)
+    }
+
+    #[inline(always)]
+    fn match_row(_: &ComponentState<'_>, _: Entity, _: usize) -> bool {
+        true
+    }
+
+    #[inline(always)]
+    unsafe fn item<'w>(state: &Self::State<'w>, entity: Entity, index: usize) -> Option<&'w T> {
+        state.0.read::<T>(entity, index)
+    }
+
+    #[inline(always)]
+    unsafe fn read_only_item<'w>(
+        state: &Self::State<'w>,
+        entity: Entity,
+        index: usize,
+    ) -> Option<&'w T> {
+        state.0.read::<T>(entity, index)
+    }
+
+    fn is_dense() -> bool {
+        !T::metadata_static().is_sparse()
+    }
+
+    #[inline(always)]
+    unsafe fn chunk<'w>(state: &Self::State<'w>, len: usize) -> Self::Chunk<'w> {
+        state.0.slice::<T>(len)
+    }
+
+    #[inline(always)]
+    unsafe fn read_only_chunk<'w>(state: &Self::State<'w>, len: usize) -> Self::ReadOnlyChunk<'w> {
+        state.0.slice::<T>(len)
+    }
+}
+
+unsafe impl<T: Component + 'static> QueryData for Option<&mut T> {
+    type Key = Option<&'static mut T>;
+    type Item<'w> = Option<&'w mut T>;
+    type ReadOnlyItem<'w> = Option<&'w T>;
+    type State<'w> = ComponentState<'w>;
+    type Chunk<'w> = Option<&'w mut [T]>;
+    type ReadOnlyChunk<'w> = Option<&'w [T]>;
+
+    fn component_access(access: &mut Access) {
+        access.add_write(T::metadata_static().id());
+    }
+
+    fn match_archetype(_: &Archetype) -> bool {
+        true
+    }
+
+    #[allow(private_interfaces)]
+    fn state<'w>(world: &'w World, table: &'w Table, ticks: SystemTicks) -> ComponentState<'w> {
+        ComponentState::new::<T>(world, table, ticks)
+    }
+
+    #[inline(always)]
+    fn match_row(_: &ComponentState<'_>, _: Entity, _: usize) -> bool {
+        true
+    }
+
+    #[inline(always)]
+    unsafe fn item<'w>(state: &Self::State<'w>, entity: Entity, index: usize) -> Option<&'w mut T> {
+        state.0.read_mut::<T>(entity, index, state.1.this_run)
+    }
+
+    #[inline(always)]
+    unsafe fn read_only_item<'w>(
+        state: &Self::State<'w>,
+        entity: Entity,
+        index: usize,
+    ) -> Option<&'w T> {
+        state.0.read::<T>(entity, index)
+    }
+
+    fn is_dense() -> bool {
+        !T::metadata_static().is_sparse()
+    }
+
+    #[inline(always)]
+    unsafe fn chunk<'w>(state: &Self::State<'w>, len: usize) -> Self::Chunk<'w> {
+        state.0.slice_mut::<T>(len, state.1.this_run)
+    }
+
+    #[inline(always)]
+    unsafe fn read_only_chunk<'w>(state: &Self::State<'w>, len: usize) -> Self::ReadOnlyChunk<'w> {
+        state.0.slice::<T>(len)
+    }
+}
+
+unsafe impl<T: Component + 'static> QueryData for With<T> {
+    type Key = Self;
+    type Item<'w> = With<T>;
+    type ReadOnlyItem<'w> = With<T>;
+    type State<'w> = ComponentState<'w>;
+    type Chunk<'w> = ();
+    type ReadOnlyChunk<'w> = ();
+
+    fn component_access(_: &mut Access) {}
+
+    fn match_archetype(archetype: &Archetype) -> bool {
+        archetype_may_contain(archetype, T::metadata_static())
+    }
+
+    #[allow(private_interfaces)]
+    fn state<'w>(world: &'w World, table: &'w Table, ticks: SystemTicks) -> ComponentState<'w> {
+        ComponentState::new::<T>(world, table, ticks)
+    }
+
+    #[inline(always)]
+    fn match_row(state: &ComponentState<'_>, entity: Entity, _: usize) -> bool {
+        state.0.contains(entity)
+    }
+
+    #[inline(always)]
+    unsafe fn item<'w>(_: &Self::State<'w>, _: Entity, _: usize) -> Self::Item<'w> {
+        With {
+            marker: PhantomData,
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn read_only_item<'w>(
+        _: &Self::State<'w>,
+        _: Entity,
+        _: usize,
+    ) -> Self::ReadOnlyItem<'w> {
+        With {
+            marker: PhantomData,
+        }
+    }
+
+    fn is_dense() -> bool {
+        !T::metadata_static().is_sparse()
+    }
+
+    #[inline(always)]
+    unsafe fn chunk<'w>(_: &Self::State<'w>, _: usize) -> Self::Chunk<'w> {}
+
+    #[inline(always)]
+    unsafe fn read_only_chunk<'w>(_: &Self::State<'w>, _: usize) -> Self::ReadOnlyChunk<'w> {}
+}
+
+unsafe impl<T: Component + 'static> QueryData for Without<T> {
+    type Key = Self;
+    type Item<'w> = Without<T>;
+    type ReadOnlyItem<'w> = Without<T>;
+    type State<'w> = ComponentState<'w>;
+    type Chunk<'w> = ();
+    type ReadOnlyChunk<'w> = ();
+
+    fn component_access(_: &mut Access) {}
+
+    fn match_archetype(archetype: &Archetype) -> bool {
+        let metadata = T::metadata_static();
+        metadata.is_sparse() || !archetype.contains(metadata)
+    }
+
+    #[allow(private_interfaces)]
+    fn state<'w>(world: &'w World, table: &'w Table, ticks: SystemTicks) -> ComponentState<'w> {
+        ComponentState::new::<T>(world, table, ticks)
+    }
+
+    #[inline(always)]
+    fn match_row(state: &ComponentState<'_>, entity: Entity, _: usize) -> bool {
+        match state.0 {
+            Fetch::Table(_) => true,
+            Fetch::Sparse(_) => !state.0.contains(entity),
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn item<'w>(_: &Self::State<'w>, _: Entity, _: usize) -> Self::Item<'w> {
+        Without {
+            marker: PhantomData,
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn read_only_item<'w>(
+        _: &Self::State<'w>,
+        _: Entity,
+        _: usize,
+    ) -> Self::ReadOnlyItem<'w> {
+        Without {
+            marker: PhantomData,
+        }
+    }
+
+    fn is_dense() -> bool {
+        !T::metadata_static().is_sparse()
+    }
+
+    #[inline(always)]
+    unsafe fn chunk<'w>(_: &Self::State<'w>, _: usize) -> Self::Chunk<'w> {}
+
+    #[inline(always)]
+    unsafe fn read_only_chunk<'w>(_: &Self::State<'w>, _: usize) -> Self::ReadOnlyChunk<'w> {}
+}
+
+unsafe impl<T: Component + 'static> QueryData for Added<T> {
+    type Key = Self;
+    type Item<'w> = Added<T>;
+    type ReadOnlyItem<'w> = Added<T>;
+    type State<'w> = ComponentState<'w>;
+    type Chunk<'w> = ();
+    type ReadOnlyChunk<'w> = ();
+
+    fn component_access(access: &mut Access) {
+        access.add_tick_read(T::metadata_static().id());
+    }
+
+    fn match_archetype(archetype: &Archetype) -> bool {
+        archetype_may_contain(archetype, T::metadata_static())
+    }
+
+    #[allow(private_interfaces)]
+    fn state<'w>(world: &'w World, table: &'w Table, ticks: SystemTicks) -> ComponentState<'w> {
+        ComponentState::new::<T>(world, table, ticks)
+    }
+
+    #[inline(always)]
+    fn match_row(state: &ComponentState<'_>, entity: Entity, index: usize) -> bool {
+        state.is_added(entity, index)
+    }
+
+    #[inline(always)]
+    unsafe fn item<'w>(_: &Self::State<'w>, _: Entity, _: usize) -> Self::Item<'w> {
+        Added {
+            marker: PhantomData,
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn read_only_item<'w>(
+        _: &Self::State<'w>,
+        _: Entity,
+        _: usize,
+    ) -> Self::ReadOnlyItem<'w> {
+        Added {
+            marker: PhantomData,
+        }
+    }
+
+    fn is_dense() -> bool {
+        false
+    }
+
+    #[inline(always)]
+    unsafe fn chunk<'w>(_: &Self::State<'w>, _: usize) -> Self::Chunk<'w> {}
+
+    #[inline(always)]
+    unsafe fn read_only_chunk<'w>(_: &Self::State<'w>, _: usize) -> Self::ReadOnlyChunk<'w> {}
+}
+
+unsafe impl<T: Component + 'static> QueryData for Changed<T> {
+    type Key = Self;
+    type Item<'w> = Changed<T>;
+    type ReadOnlyItem<'w> = Changed<T>;
+    type State<'w> = ComponentState<'w>;
+    type Chunk<'w> = ();
+    type ReadOnlyChunk<'w> = ();
+
+    fn component_access(access: &mut Access) {
+        access.add_tick_read(T::metadata_static().id());
+    }
+
+    fn match_archetype(archetype: &Archetype) -> bool {
+        archetype_may_contain(archetype, T::metadata_static())
+    }
+
+    #[allow(private_interfaces)]
+    fn state<'w>(world: &'w World, table: &'w Table, ticks: SystemTicks) -> ComponentState<'w> {
+        ComponentState::new::<T>(world, table, ticks)
+    }
+
+    #[inline(always)]
+    fn match_row(state: &ComponentState<'_>, entity: Entity, index: usize) -> bool {
+        state.is_changed(entity, index)
+    }
+
+    #[inline(always)]
+    unsafe fn item<'w>(_: &Self::State<'w>, _: Entity, _: usize) -> Self::Item<'w> {
+        Changed {
+            marker: PhantomData,
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn read_only_item<'w>(
+        _: &Self::State<'w>,
+        _: Entity,
+        _: usize,
+    ) -> Self::ReadOnlyItem<'w> {
+        Changed {
+            marker: PhantomData,
+        }
+    }
+
+    fn is_dense() -> bool {
+        false
+    }
+
+    #[inline(always)]
+    unsafe fn chunk<'w>(_: &Self::State<'w>, _: usize) -> Self::Chunk<'w> {}
+
+    #[inline(always)]
+    unsafe fn read_only_chunk<'w>(_: &Self::State<'w>, _: usize) -> Self::ReadOnlyChunk<'w> {}
+}
+
+macro_rules! impl_query_data {
+    ($(($q:ident, $idx:tt)),+) => {
+        unsafe impl<$($q: QueryData,)+> QueryData for ($($q,)+) {
+            type Key = ($($q::Key,)+);
+            type Item<'w> = ($($q::Item<'w>,)+);
+            type ReadOnlyItem<'w> = ($($q::ReadOnlyItem<'w>,)+);
+            type State<'w> = ($($q::State<'w>,)+);
+            type Chunk<'w> = ($($q::Chunk<'w>,)+);
+            type ReadOnlyChunk<'w> = ($($q::ReadOnlyChunk<'w>,)+);
+
+            fn component_access(access: &mut Access) {
+                $($q::component_access(access);)+
+            }
+
+            fn match_archetype(archetype: &Archetype) -> bool {
+                $($q::match_archetype(archetype))&&+
+            }
+
+            #[allow(private_interfaces)]
+            fn state<'w>(world: &'w World, table: &'w Table, ticks: SystemTicks) -> Self::State<'w> {
+                ($($q::state(world, table, ticks),)+)
+            }
+
+            #[inline(always)]
+            fn match_row(state: &Self::State<'_>, entity: Entity, index: usize) -> bool {
+                $($q::match_row(&state.$idx, entity, index))&&+
+            }
+
+            #[inline(always)]
+            unsafe fn item<'w>(
+                state: &Self::State<'w>,
+                entity: Entity,
+                index: usize,
+            ) -> Self::Item<'w> {
+                ($($q::item(&state.$idx, entity, index),)+)
+            }
+
+            #[inline(always)]
+            unsafe fn read_only_item<'w>(
+                state: &Self::State<'w>,
+                entity: Entity,
+                index: usize,
+            ) -> Self::ReadOnlyItem<'w> {
+                ($($q::read_only_item(&state.$idx, entity, index),)+)
+            }
+
+            fn is_dense() -> bool {
+                $($q::is_dense())&&+
+            }
+
+            #[inline(always)]
+            unsafe fn chunk<'w>(state: &Self::State<'w>, len: usize) -> Self::Chunk<'w> {
+                ($($q::chunk(&state.$idx, len),)+)
+            }
+
+            #[inline(always)]
+            unsafe fn read_only_chunk<'w>(state: &Self::State<'w>, len: usize) -> Self::ReadOnlyChunk<'w> {
+                ($($q::read_only_chunk(&state.$idx, len),)+)
+            }
+        }
+    }
+}
+
+impl_query_data!((Q1, 0));
+impl_query_data!((Q1, 0), (Q2, 1));
+impl_query_data!((Q1, 0), (Q2, 1), (Q3, 2));
+impl_query_data!((Q1, 0), (Q2, 1), (Q3, 2), (Q4, 3));
+impl_query_data!((Q1, 0), (Q2, 1), (Q3, 2), (Q4, 3), (Q5, 4));
+impl_query_data!((Q1, 0), (Q2, 1), (Q3, 2), (Q4, 3), (Q5, 4), (Q6, 5));
+impl_query_data!(
+    (Q1, 0),
+    (Q2, 1),
+    (Q3, 2),
+    (Q4, 3),
+    (Q5, 4),
+    (Q6, 5),
+    (Q7, 6)
+);
+impl_query_data!(
+    (Q1, 0),
+    (Q2, 1),
+    (Q3, 2),
+    (Q4, 3),
+    (Q5, 4),
+    (Q6, 5),
+    (Q7, 6),
+    (Q8, 7)
+);
+impl_query_data!(
+    (Q1, 0),
+    (Q2, 1),
+    (Q3, 2),
+    (Q4, 3),
+    (Q5, 4),
+    (Q6, 5),
+    (Q7, 6),
+    (Q8, 7),
+    (Q9, 8)
+);
+impl_query_data!(
+    (Q1, 0),
+    (Q2, 1),
+    (Q3, 2),
+    (Q4, 3),
+    (Q5, 4),
+    (Q6, 5),
+    (Q7, 6),
+    (Q8, 7),
+    (Q9, 8),
+    (Q10, 9)
+);
+impl_query_data!(
+    (Q1, 0),
+    (Q2, 1),
+    (Q3, 2),
+    (Q4, 3),
+    (Q5, 4),
+    (Q6, 5),
+    (Q7, 6),
+    (Q8, 7),
+    (Q9, 8),
+    (Q10, 9),
+    (Q11, 10)
+);
+impl_query_data!(
+    (Q1, 0),
+    (Q2, 1),
+    (Q3, 2),
+    (Q4, 3),
+    (Q5, 4),
+    (Q6, 5),
+    (Q7, 6),
+    (Q8, 7),
+    (Q9, 8),
+    (Q10, 9),
+    (Q11, 10),
+    (Q12, 11)
+);
+
+/// The entities matching `Q`, created by [`World::query`]. Holds the borrows of the components
+/// `Q` accesses until it is dropped, so structural changes are deferred until then, and it can
+/// be iterated as many times as needed in the meantime.
+pub struct Query<'w, Q: QueryData> {
+    world: &'w World,
+    access: Access,
+    ticks: SystemTicks,
+    tables: Arc<[TableId]>,
+    batch_size: usize,
+    marker: PhantomData<Q>,
+}
+
+impl<'w, Q: QueryData> Query<'w, Q> {
+    pub(crate) fn new(world: &'w World, access: Access, ticks: SystemTicks) -> Self {
+        Query {
+            world,
+            access,
+            ticks,
+            tables: world.matching_tables::<Q::Key>(Q::match_archetype),
+            batch_size: 1024,
+            marker: PhantomData,
+        }
+    }
+
+    /// Sets the maximum number of rows [`Query::par_for_each`] hands to a thread at a time. Every
+    /// batch is from a single table. Defaults to 1024.
+    ///
+    /// # Panics
+    ///
+    /// If `batch_size` is 0
+    pub fn set_batch_size(&mut self, batch_size: usize) -> &mut Self {
+        assert!(batch_size > 0, "the batch size cannot be 0");
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Calls `f` on every item, spreading batches of rows over as many threads as the machine
+    /// has, e.g.
+    /// ```ignore
+    ///   world.query::<(&Position, &Lifetime)>().par_for_each(|(pos, lifetime), commands| {
+    ///       if lifetime.0 == 0 {
+    ///           commands.spawn(Explosion(*pos));
+    ///       }
+    ///   });
+    /// ```
+    /// Every batch gets its own [`ParCommands`], which are queued on the world in the order of
+    /// the rows once all the batches are done. The items must be `Send`, i.e. the components
+    /// read must be `Sync`.
+    pub fn par_for_each<'q, F>(&'q self, f: F)
+    where
+        F: Fn(Q::ReadOnlyItem<'q>, &mut ParCommands<'q>) + Sync,
+        Q::ReadOnlyItem<'q>: Send,
+    {
+        // Items are read-only so the rows may be visited in any order
+        self.for_each_batch(|state, entity, index, commands| {
+            f(unsafe { Q::read_only_item(state, entity, index) }, commands)
+        });
+    }
+
+    /// Same as [`Query::par_for_each`] with mutable items, the components written must be `Send`
+    pub fn par_for_each_mut<'q, F>(&'q mut self, f: F)
+    where
+        F: Fn(Q::Item<'q>, &mut ParCommands<'q>) + Sync,
+        Q::Item<'q>: Send,
+    {
+        // Every row is in exactly one batch, so the mutable items never alias
+        self.for_each_batch(|state, entity, index, commands| {
+            f(unsafe { Q::item(state, entity, index) }, commands)
+        });
+    }
+
+    fn for_each_batch<'q>(
+        &'q self,
+        f: impl Fn(&Q::State<'q>, Entity, usize, &mut ParCommands<'q>) + Sync,
+    ) {
+        let mut batches: Vec<(TableId, Range<usize>)> = Vec::new();
+        for table_id in self.tables.iter() {
+            let len = self.world.table(*table_id).len();
+            batches.extend(
+                (0..len)
+                    .step_by(self.batch_size)
+                    .map(|start| (*table_id, start..len.min(start + self.batch_size))),
+            );
+        }
+
+        let (world, ticks) = (self.world, self.ticks);
+        let next = AtomicUsize::new(0);
+        let queued: Mutex<Vec<(usize, SendCmds)>> = Mutex::new(Vec::new());
+        let queued = &queued;
+        let worker = || {
+            let mut own = Vec::new();
+            loop {
+                let batch = next.fetch_add(1, Ordering::Relaxed);
+                let Some((table_id, rows)) = batches.get(batch) else {
+                    break;
+                };
+                let table = world.table(*table_id);
+                let state = Q::state(world, table, ticks);
+                let entities = unsafe { table.get_column::<Entity>().unwrap_unchecked() };
+                let mut commands = ParCommands::new(world);
+                for index in rows.clone() {
+                    let entity = unsafe { *entities.read::<Entity>(index) };
+                    if Q::match_row(&state, entity, index) {
+                        f(&state, entity, index, &mut commands);
+                    }
+                }
+                let cmds = commands.into_buffer();
+                if !cmds.0.is_empty() {
+                    own.push((batch, cmds));
+                }
+            }
+            queued.lock().unwrap().append(&mut own);
+        };
+
+        let num_threads = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+        thread::scope(|scope| {
+            for _ in 1..num_threads.min(batches.len()) {
+                scope.spawn(worker);
+            }
+            worker();
+        });
+
+        let mut queued = mem::take(&mut *queued.lock().unwrap());
+        queued.sort_by_key(|(batch, _)| *batch);
+        for (_, cmds) in queued {
+            world.append_cmds(cmds.0);
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_, Q> {
+        Iter {
+            rows: Rows::new(self.world, &self.tables, self.ticks),
+        }
+    }
+
+    pub fn iter_mut(&mut self) -> IterMut<'_, Q> {
+        IterMut {
+            rows: Rows::new(self.world, &self.tables, self.ticks),
+        }
+    }
+
+    /// Iterates over the matching tables, giving the items of all their rows at once as slices,
+    /// e.g. `(&[Entity], &[Position], &[Velocity])` for `(&Entity, &Position, &Velocity)`. Rows
+    /// are kept dense, so every slice of a chunk has the same length.
+    ///
+    /// # Panics
+    ///
+    /// If `Q` is not dense, see [`QueryData::is_dense`]
+    pub fn chunks(&self) -> Chunks<'_, Q> {
+        Chunks {
+            tables: Tables::new(self.world, &self.tables, self.ticks),
+        }
+    }
+
+    /// Same as [`Query::chunks`] with mutable slices, e.g. `(&[Position], &mut [Velocity])`
+    pub fn chunks_mut(&mut self) -> ChunksMut<'_, Q> {
+        ChunksMut {
+            tables: Tables::new(self.world, &self.tables, self.ticks),
+        }
+    }
+
+    /// Returns the entity's item, or `None` if it is dead or does not match
+    pub fn get(&self, entity: Entity) -> Option<Q::ReadOnlyItem<'_>> {
+        let (state, index) = self.row(entity)?;
+        Some(unsafe { Q::read_only_item(&state, entity, index) })
+    }
+
+    pub fn get_mut(&mut self, entity: Entity) -> Option<Q::Item<'_>> {
+        let (state, index) = self.row(entity)?;
+        Some(unsafe { Q::item(&state, entity, index) })
+    }
+
+    /// Returns the item of the only matching entity, or `None` if there are zero or several
+    pub fn single(&self) -> Option<Q::ReadOnlyItem<'_>> {
+        let mut iter = self.iter();
+        let item = iter.next()?;
+        iter.next().is_none().then_some(item)
+    }
+
+    pub fn single_mut(&mut self) -> Option<Q::Item<'_>> {
+        let mut iter = self.iter_mut();
+        let item = iter.next()?;
+        iter.next().is_none().then_some(item)
+    }
+
+    pub fn count(&self) -> usize {
+        Rows::<Q>::new(self.world, &self.tables, self.ticks).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        Rows::<Q>::new(self.world, &self.tables, self.ticks)
+            .next()
+            .is_none()
+    }
+
+    fn row(&self, entity: Entity) -> Option<(Q::State<'_>, usize)> {
+        let (table_id, index) = self.world.location(entity)?;
+        let table = self.world.table(table_id);
+        if !Q::match_archetype(table.archetype()) {
+            return None;
+        }
+        let state = Q::state(self.world, table, self.ticks);
+        Q::match_row(&state, entity, index).then_some((state, index))
+    }
+}
+
+impl<Q: QueryData> Drop for Query<'_, Q> {
+    fn drop(&mut self) {
+        self.world.release(&self.access);
+    }
+}
+
+impl<'q, Q: QueryData> IntoIterator for &'q Query<'_, Q> {
+    type Item = Q::ReadOnlyItem<'q>;
+    type IntoIter = Iter<'q, Q>;
+
+    fn into_iter(self) -> Iter<'q, Q> {
+        self.iter()
+    }
+}
+
+impl<'q, Q: QueryData> IntoIterator for &'q mut Query<'_, Q> {
+    type Item = Q::Item<'q>;
+    type IntoIter = IterMut<'q, Q>;
+
+    fn into_iter(self) -> IterMut<'q, Q> {
+        self.iter_mut()
+    }
+}
+
+/// Walks the matching rows of the matched tables
+struct Rows<'q, Q: QueryData> {
+    world: &'q World,
+    tables: &'q [TableId],
+    ticks: SystemTicks,
+    state: Option<Q::State<'q>>,
+    entities: Option<&'q Column>,
+    index: usize,
+    len: usize,
+}
+
+impl<'q, Q: QueryData> Rows<'q, Q> {
+    fn new(world: &'q World, tables: &'q [TableId], ticks: SystemTicks) -> Self {
+        Rows {
+            world,
+            tables,
+            ticks,
+            state: None,
+            entities: None,
+            index: 0,
+            len: 0,
+        }
+    }
+}
+
+impl<'q, Q: QueryData> Iterator for Rows<'q, Q> {
+    type Item = (Entity, usize);
+
+    #[inline]
+    fn next(&mut self) -> Option<(Entity, usize)> {
+        loop {
+            while self.index < self.len {
+                let index = self.index;
+                self.index += 1;
+                let entity = unsafe { *self.entities?.read::<Entity>(index) };
+                let state = unsafe { self.state.as_ref().unwrap_unchecked() };
+                if Q::match_row(state, entity, index) {
+                    return Some((entity, index));
+                }
+            }
+
+            let (table_id, rest) = self.tables.split_first()?;
+            self.tables = rest;
+            let table = self.world.table(*table_id);
+            if table.len() == 0 {
+                continue;
+            }
+            self.state = Some(Q::state(self.world, table, self.ticks));
+            self.entities = unsafe { table.get_column::<Entity>() };
+            self.index = 0;
+            self.len = table.len();
+        }
+    }
+}
+
+/// Walks the non-empty matched tables
+struct Tables<'q, Q: QueryData> {
+    world: &'q World,
+    tables: &'q [TableId],
+    ticks: SystemTicks,
+    marker: PhantomData<Q>,
+}
+
+impl<'q, Q: QueryData> Tables<'q, Q> {
+    fn new(world: &'q World, tables: &'q [TableId], ticks: SystemTicks) -> Self {
+        if !Q::is_dense() {
+            panic!(
+                "{} has filters that are checked per row, it cannot be chunked",
+                type_name::<Q>()
+            );
+        }
+        Tables {
+            world,
+            tables,
+            ticks,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<'q, Q: QueryData> Iterator for Tables<'q, Q> {
+    type Item = (Q::State<'q>, usize);
+
+    fn next(&mut self) -> Option<(Q::State<'q>, usize)> {
+        loop {
+            let (table_id, rest) = self.tables.split_first()?;
+            self.tables = rest;
+            let table = self.world.table(*table_id);
+            if table.len() > 0 {
+                return Some((Q::state(self.world, table, self.ticks), table.len()));
+            }
+        }
+    }
+}
+
+/// Iterates over the read-only chunks of a [`Query`], one per table
+pub struct Chunks<'q, Q: QueryData> {
+    tables: Tables<'q, Q>,
+}
+
+impl<'q, Q: QueryData> Iterator for Chunks<'q, Q> {
+    type Item = Q::ReadOnlyChunk<'q>;
+
+    fn next(&mut self) -> Option<Q::ReadOnlyChunk<'q>> {
+        let (state, len) = self.tables.next()?;
+        Some(unsafe { Q::read_only_chunk(&state, len) })
+    }
+}
+
+/// Iterates over the chunks of a [`Query`], one per table
+pub struct ChunksMut<'q, Q: QueryData> {
+    tables: Tables<'q, Q>,
+}
+
+impl<'q, Q: QueryData> Iterator for ChunksMut<'q, Q> {
+    type Item = Q::Chunk<'q>;
+
+    fn next(&mut self) -> Option<Q::Chunk<'q>> {
+        let (state, len) = self.tables.next()?;
+        // Every table is visited once, so the mutable chunks never alias
+        Some(unsafe { Q::chunk(&state, len) })
+    }
+}
+
+/// Iterates over the read-only items of a [`Query`]
+pub struct Iter<'q, Q: QueryData> {
+    rows: Rows<'q, Q>,
+}
+
+impl<'q, Q: QueryData> Iterator for Iter<'q, Q> {
+    type Item = Q::ReadOnlyItem<'q>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Q::ReadOnlyItem<'q>> {
+        let (entity, index) = self.rows.next()?;
+        let state = unsafe { self.rows.state.as_ref().unwrap_unchecked() };
+        Some(unsafe { Q::read_only_item(state, entity, index) })
+    }
+}
+
+/// Iterates over the items of a [`Query`]
+pub struct IterMut<'q, Q: QueryData> {
+    rows: Rows<'q, Q>,
+}
+
+impl<'q, Q: QueryData> Iterator for IterMut<'q, Q> {
+    type Item = Q::Item<'q>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Q::Item<'q>> {
+        let (entity, index) = self.rows.next()?;
+        let state = unsafe { self.rows.state.as_ref().unwrap_unchecked() };
+        // Every row is visited once, so the mutable items never alias
+        Some(unsafe { Q::item(state, entity, index) })
+    }
+}
@@ -0,0 +1,148 @@
+use std::{any::TypeId, marker::PhantomData};
+
+use crate::{
+    archetype::Archetype,
+    borrow::Access,
+    change::SystemTicks,
+    component::{Component, Metadata},
+    table::{Column, Table},
+    Entity, ParallelParam, QueryParam, World,
+};
+
+/// The entities that lost a component since the log was last drained, see
+/// [`World::track_removed`]
+pub(crate) struct RemovedLog {
+    entities: Vec<Entity>,
+    // The removed values, if they are kept, one row per entry of `entities` from `values_start`
+    values: Option<Column>,
+    // The entries logged before the values were kept have none
+    values_start: usize,
+}
+
+impl RemovedLog {
+    pub fn new() -> Self {
+        RemovedLog {
+            entities: Vec::new(),
+            values: None,
+            values_start: 0,
+        }
+    }
+
+    pub fn keep_values(&mut self, metadata: Metadata) {
+        if self.values.is_none() {
+            self.values = Some(Column::from_metadata(metadata));
+            self.values_start = self.entities.len();
+        }
+    }
+
+    fn num_values(&self) -> usize {
+        match self.values {
+            Some(_) => self.entities.len() - self.values_start,
+            None => 0,
+        }
+    }
+
+    /// Records that `entity` lost the item at `index` of `col`, moving the item into the log or
+    /// dropping it. Either way the slot is left uninitialized.
+    pub unsafe fn push(&mut self, entity: Entity, col: &mut Column, index: usize) {
+        let row = self.num_values();
+        match self.values.as_mut() {
+            Some(values) => Column::copy_item_from_column(col, values, index, row),
+            None => col.drop_item(index),
+        }
+        self.entities.push(entity);
+    }
+
+    /// Empties the log. The values are `None` unless they are kept, including for the entries
+    /// logged before they were.
+    ///
+    /// # Safety
+    ///
+    /// `T` must be the component the log is for
+    pub unsafe fn drain<T: Component + 'static>(&mut self) -> Vec<(Entity, Option<T>)> {
+        let values = self.values.as_ref();
+        let start = self.values_start;
+        let removed = self
+            .entities
+            .iter()
+            .enumerate()
+            .map(|(i, entity)| {
+                let value = values
+                    .filter(|_| i >= start)
+                    .map(|col| (col.read::<T>(i - start) as *const T).read());
+                (*entity, value)
+            })
+            .collect();
+        self.entities.clear();
+        self.values_start = 0;
+        removed
+    }
+}
+
+impl Drop for RemovedLog {
+    fn drop(&mut self) {
+        let num_values = self.num_values();
+        if let Some(values) = self.values.as_mut() {
+            for row in 0..num_values {
+                unsafe { values.drop_item(row) };
+            }
+        }
+    }
+}
+
+/// Drains the log of the entities that lost `T`, see [`World::removed`]. A system with only
+/// `RemovedComponents`, resources and [`Commands`](crate::Commands) runs once per run, even in an
+/// empty world. Alongside components it is created for every matching entity, so the first call
+/// to `drain` in a run gets everything.
+/// ```ignore
+///   world.run(|mut removed: RemovedComponents<RigidBody>| {
+///       for (entity, _) in removed.drain() {
+///           physics.remove_body(entity);
+///       }
+///   });
+/// ```
+pub struct RemovedComponents<'w, T: Component> {
+    world: &'w World,
+    marker: PhantomData<T>,
+}
+
+impl<T: Component + 'static> RemovedComponents<'_, T> {
+    pub fn drain(&mut self) -> Vec<(Entity, Option<T>)> {
+        self.world.removed::<T>()
+    }
+}
+
+// The removed values are moved to the thread draining them
+unsafe impl<T: Component + Send> ParallelParam for RemovedComponents<'_, T> {}
+
+impl<'a, T: Component + 'static> QueryParam<'a, T, RemovedComponents<'a, T>>
+    for RemovedComponents<'a, T>
+{
+    type Key = RemovedComponents<'static, T>;
+    type State = &'a World;
+
+    fn state(world: &'a World, _: &'a Table, _: SystemTicks) -> &'a World {
+        world
+    }
+
+    fn run_state(world: &'a World, _: SystemTicks) -> Option<&'a World> {
+        Some(world)
+    }
+
+    #[inline(always)]
+    fn access(world: &&'a World, _: Entity, _: usize) -> RemovedComponents<'a, T> {
+        RemovedComponents {
+            world,
+            marker: PhantomData,
+        }
+    }
+
+    fn match_archetype(_: &Archetype) -> bool {
+        true
+    }
+
+    // Draining is a side effect on the log of `T`
+    fn component_access(access: &mut Access) {
+        access.add_side_effect(TypeId::of::<RemovedComponents<'static, T>>());
+    }
+}
@@ -0,0 +1,188 @@
+use std::{
+    any::{type_name, Any, TypeId},
+    cell::Cell,
+    collections::HashMap,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    sync::{OnceLock, RwLock},
+};
+
+use crate::{
+    archetype::Archetype,
+    borrow::Access,
+    change::{ComponentTicks, SystemTicks},
+    table::Table,
+    Entity, ParallelParam, QueryParam, World,
+};
+
+/// A resource and when it was inserted and last borrowed mutably, like the ticks of components
+pub(crate) struct ResourceData {
+    pub value: Box<dyn Any + Send + Sync>,
+    pub ticks: Cell<ComponentTicks>,
+}
+
+/// Identifies a resource type. Resources have their own id space, separate from components.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct ResourceId(pub u32);
+
+/// Hands out `ResourceId`s to resource types the first time they are used, like the component
+/// registry does for components
+struct Registry {
+    ids: HashMap<TypeId, ResourceId>,
+    names: Vec<&'static str>,
+}
+
+fn registry() -> &'static RwLock<Registry> {
+    static REGISTRY: OnceLock<RwLock<Registry>> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        RwLock::new(Registry {
+            ids: HashMap::new(),
+            names: Vec::new(),
+        })
+    })
+}
+
+pub(crate) fn resource_id<R: 'static>() -> ResourceId {
+    if let Some(id) = registry().read().unwrap().ids.get(&TypeId::of::<R>()) {
+        return *id;
+    }
+
+    let mut registry = registry().write().unwrap();
+    let next = ResourceId(registry.names.len() as u32);
+    let id = *registry.ids.entry(TypeId::of::<R>()).or_insert(next);
+    if id == next {
+        registry.names.push(type_name::<R>());
+    }
+    id
+}
+
+/// The name of the resource type, for error messages
+pub(crate) fn name_of(id: ResourceId) -> &'static str {
+    registry()
+        .read()
+        .unwrap()
+        .names
+        .get(id.0 as usize)
+        .copied()
+        .unwrap_or("<unknown>")
+}
+
+fn missing<R>() -> ! {
+    panic!("resource {} does not exist", type_name::<R>())
+}
+
+/// A shared borrow of the resource `R` as a system parameter, see [`World::insert_resource`]. A
+/// system with only resources, [`Commands`](crate::Commands) and
+/// [`RemovedComponents`](crate::RemovedComponents) runs once per run, alongside components it is
+/// passed once per matching entity.
+///
+/// # Panics
+///
+/// The system panics if the resource does not exist
+pub struct Res<'w, R> {
+    value: &'w R,
+}
+
+impl<R> Deref for Res<'_, R> {
+    type Target = R;
+
+    fn deref(&self) -> &R {
+        self.value
+    }
+}
+
+unsafe impl<R: Send + Sync> ParallelParam for Res<'_, R> {}
+
+impl<'a, R: Send + Sync + 'static> QueryParam<'a, R, Res<'a, R>> for Res<'a, R> {
+    type Key = Res<'static, R>;
+    type State = &'a R;
+
+    fn state(world: &'a World, _: &'a Table, _: SystemTicks) -> &'a R {
+        match world.resource_ptr::<R>() {
+            Some(ptr) => unsafe { &*ptr },
+            None => missing::<R>(),
+        }
+    }
+
+    fn run_state(world: &'a World, _: SystemTicks) -> Option<&'a R> {
+        world
+            .resource_ptr::<R>()
+            .map(|ptr| unsafe { &*ptr })
+            .or_else(|| missing::<R>())
+    }
+
+    #[inline(always)]
+    fn access(value: &&'a R, _: Entity, _: usize) -> Res<'a, R> {
+        Res { value }
+    }
+
+    fn match_archetype(_: &Archetype) -> bool {
+        true
+    }
+
+    fn component_access(access: &mut Access) {
+        access.add_resource_read(resource_id::<R>());
+    }
+}
+
+/// A mutable borrow of the resource `R` as a system parameter, see [`Res`]. Marks the resource as
+/// changed like `&mut T` does for components.
+pub struct ResMut<'w, R> {
+    value: *mut R,
+    marker: PhantomData<&'w mut R>,
+}
+
+impl<R> Deref for ResMut<'_, R> {
+    type Target = R;
+
+    fn deref(&self) -> &R {
+        unsafe { &*self.value }
+    }
+}
+
+impl<R> DerefMut for ResMut<'_, R> {
+    fn deref_mut(&mut self) -> &mut R {
+        unsafe { &mut *self.value }
+    }
+}
+
+unsafe impl<R: Send + Sync> ParallelParam for ResMut<'_, R> {}
+
+impl<'a, R: Send + Sync + 'static> QueryParam<'a, R, ResMut<'a, R>> for ResMut<'a, R> {
+    type Key = ResMut<'static, R>;
+    type State = (*mut R, &'a Cell<ComponentTicks>, u32);
+
+    fn state(world: &'a World, _: &'a Table, ticks: SystemTicks) -> Self::State {
+        let (value, resource_ticks) = world
+            .resource_entry::<R>()
+            .unwrap_or_else(|| missing::<R>());
+        (value, resource_ticks, ticks.this_run)
+    }
+
+    fn run_state(world: &'a World, ticks: SystemTicks) -> Option<Self::State> {
+        let (value, resource_ticks) = world
+            .resource_entry::<R>()
+            .unwrap_or_else(|| missing::<R>());
+        Some((value, resource_ticks, ticks.this_run))
+    }
+
+    #[inline(always)]
+    fn access(&(value, ticks, this_run): &Self::State, _: Entity, _: usize) -> ResMut<'a, R> {
+        ticks.set(ComponentTicks {
+            changed: this_run,
+            ..ticks.get()
+        });
+        ResMut {
+            value,
+            marker: PhantomData,
+        }
+    }
+
+    fn match_archetype(_: &Archetype) -> bool {
+        true
+    }
+
+    fn component_access(access: &mut Access) {
+        access.add_resource_write(resource_id::<R>());
+    }
+}
@@ -0,0 +1,730 @@
+use std::{
+    any::{type_name, Any, TypeId},
+    marker::PhantomData,
+    mem,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex},
+    thread::{self, JoinHandle},
+};
+
+use crate::{
+    borrow::{Access, BorrowError},
+    change::SystemTicks,
+    commands::Cmd,
+    error::ScheduleError,
+    LastRun, ParallelParam, System, World,
+};
+
+/// A [`System`] with its parameter types erased, so that systems with different parameters can
+/// be stored together
+trait ErasedSystem<'w> {
+    unsafe fn run(&mut self, world: &'w World, ticks: SystemTicks);
+}
+
+struct Erased<F, Params> {
+    f: F,
+    marker: PhantomData<fn(Params)>,
+}
+
+impl<'w, F: System<'w, Params>, Params> ErasedSystem<'w> for Erased<F, Params> {
+    unsafe fn run(&mut self, world: &'w World, ticks: SystemTicks) {
+        self.f.run(world, ticks);
+    }
+}
+
+type Condition<'w> = Box<dyn FnMut(&World) -> bool + Send + 'w>;
+
+/// A system added to a [`Schedule`], returned by [`Schedule::add_system`] to configure it
+pub struct ScheduledSystem<'w> {
+    name: &'static str,
+    system: Box<dyn ErasedSystem<'w> + Send + 'w>,
+    access: Access,
+    labels: Vec<&'static str>,
+    before: Vec<&'static str>,
+    after: Vec<&'static str>,
+    conditions: Vec<Condition<'w>>,
+    last_run: u32,
+}
+
+impl<'w> ScheduledSystem<'w> {
+    /// Names the system for `before`/`after` constraints and error messages. Several systems may
+    /// share a label.
+    pub fn label(&mut self, label: &'static str) -> &mut Self {
+        self.labels.push(label);
+        self
+    }
+
+    /// Runs the system before the systems of the same stage that have the label, of which there
+    /// must be at least one
+    pub fn before(&mut self, label: &'static str) -> &mut Self {
+        self.before.push(label);
+        self
+    }
+
+    /// Runs the system after the systems of the same stage that have the label, of which there
+    /// must be at least one
+    pub fn after(&mut self, label: &'static str) -> &mut Self {
+        self.after.push(label);
+        self
+    }
+
+    /// Only runs the system when `condition` returns true, e.g.
+    /// ```ignore
+    ///   schedule
+    ///       .add_system("update", apply_velocity)
+    ///       .run_if(|world| !world.resource::<Paused>().unwrap().0);
+    ///   let mut frame = 0;
+    ///   schedule.add_system("update", autosave).run_if(move |_| {
+    ///       frame += 1;
+    ///       frame % 600 == 0
+    ///   });
+    /// ```
+    /// The conditions of a stage are checked on the thread calling [`Schedule::run`] when the
+    /// stage starts, before any of its systems run. If there are several all of them are called,
+    /// and the system runs if they all return true. A system that does not run keeps its last run
+    /// for [`Added`](crate::Added) and [`Changed`](crate::Changed), so it sees what changed in
+    /// the meantime the next time it runs. [`resource_changed`] runs a system when a resource
+    /// changed.
+    pub fn run_if(&mut self, condition: impl FnMut(&World) -> bool + Send + 'w) -> &mut Self {
+        self.conditions.push(Box::new(condition));
+        self
+    }
+
+    fn display_name(&self) -> &'static str {
+        self.labels.first().copied().unwrap_or(self.name)
+    }
+}
+
+struct Stage<'w> {
+    name: &'static str,
+    systems: Vec<ScheduledSystem<'w>>,
+    // `None` until computed again after a system is added
+    plan: Option<Plan>,
+}
+
+/// How the systems of a stage run. Positions are indices into `order`.
+struct Plan {
+    // The systems in the order a serial run runs them in
+    order: Vec<usize>,
+    // The positions that wait for each position: the later systems that are ordered after it or
+    // conflict with it, so that a parallel run has the same results as a serial one
+    dependents: Vec<Vec<usize>>,
+    num_deps: Vec<usize>,
+    // The positions of the systems that queue commands, which a parallel run runs on the calling
+    // thread so that the components they queue never cross threads
+    on_caller: Vec<bool>,
+}
+
+impl<'w> Stage<'w> {
+    fn plan(&self) -> Result<Plan, ScheduleError> {
+        let (order, edges) = self.sort()?;
+        let n = order.len();
+        let mut dependents = vec![Vec::new(); n];
+        let mut num_deps = vec![0; n];
+        for a in 0..n {
+            let first = &self.systems[order[a]];
+            for b in a + 1..n {
+                let second = &self.systems[order[b]];
+                if edges[order[a]].contains(&order[b])
+                    || !first.access.is_parallel_with(&second.access)
+                {
+                    dependents[a].push(b);
+                    num_deps[b] += 1;
+                }
+            }
+        }
+        let on_caller = order
+            .iter()
+            .map(|index| {
+                let access = &self.systems[*index].access;
+                access.has_side_effect(TypeId::of::<Cmd>())
+            })
+            .collect();
+        Ok(Plan {
+            order,
+            dependents,
+            num_deps,
+            on_caller,
+        })
+    }
+
+    /// Sorts the systems so that every constraint holds, keeping the order they were added in
+    /// where the constraints leave a choice. Also returns the constraints as edges from every
+    /// system to the ones that run after it.
+    #[allow(clippy::type_complexity)]
+    fn sort(&self) -> Result<(Vec<usize>, Vec<Vec<usize>>), ScheduleError> {
+        let n = self.systems.len();
+        let has_label = |i: usize, label: &str| self.systems[i].labels.contains(&label);
+
+        let mut edges = vec![Vec::new(); n];
+        for (i, system) in self.systems.iter().enumerate() {
+            for label in &system.before {
+                edges[i].extend((0..n).filter(|j| *j != i && has_label(*j, label)));
+            }
+            for label in &system.after {
+                for j in (0..n).filter(|j| *j != i && has_label(*j, label)) {
+                    edges[j].push(i);
+                }
+            }
+        }
+
+        let mut num_deps = vec![0; n];
+        for targets in &edges {
+            for j in targets {
+                num_deps[*j] += 1;
+            }
+        }
+
+        let mut order = Vec::with_capacity(n);
+        let mut done = vec![false; n];
+        while order.len() < n {
+            let Some(next) = (0..n).find(|i| !done[*i] && num_deps[*i] == 0) else {
+                return Err(self.cycle(&edges, &done));
+            };
+            done[next] = true;
+            order.push(next);
+            for j in &edges[next] {
+                num_deps[*j] -= 1;
+            }
+        }
+        Ok((order, edges))
+    }
+
+    /// Every system left over by the sort waits on another left-over one, so walking back from
+    /// any of them to a system it waits on ends up in a cycle. Walking forward does not, a
+    /// left-over system can be followed by none.
+    fn cycle(&self, edges: &[Vec<usize>], done: &[bool]) -> ScheduleError {
+        let waits_on = |i: usize| {
+            (0..done.len())
+                .find(|j| !done[*j] && edges[*j].contains(&i))
+                .unwrap()
+        };
+
+        let mut path = vec![(0..done.len()).find(|i| !done[*i]).unwrap()];
+        loop {
+            let prev = waits_on(*path.last().unwrap());
+            if let Some(start) = path.iter().position(|i| *i == prev) {
+                // The path runs backwards, from each system to the one before it
+                let systems = std::iter::once(prev)
+                    .chain(path[start + 1..].iter().rev().copied())
+                    .chain(std::iter::once(prev))
+                    .map(|i| self.systems[i].display_name())
+                    .collect();
+                return ScheduleError::Cycle {
+                    stage: self.name,
+                    systems,
+                };
+            }
+            path.push(prev);
+        }
+    }
+}
+
+/// Runs systems in a fixed order: stage by stage in the order they were added, and within a stage
+/// in the order the systems were added unless `before`/`after` constraints say otherwise.
+/// Structural changes are deferred until the end of each stage.
+/// ```ignore
+///   let mut schedule = Schedule::new();
+///   schedule.add_stage("update").add_stage("render");
+///   schedule.add_system("update", apply_velocity).label("movement").after("input");
+///   schedule.add_system("update", read_input).label("input");
+///   schedule.add_system("render", draw);
+///   loop {
+///       schedule.run(&world);
+///   }
+/// ```
+/// Every system has its own last run for [`Added`](crate::Added) and
+/// [`Changed`](crate::Changed).
+///
+/// With [`Schedule::set_num_threads`] the systems of a stage run in parallel, as far as the
+/// components and resources they access allow. Systems that conflict still run in the order above,
+/// so the results are the same as with a single thread.
+pub struct Schedule<'w> {
+    stages: Vec<Stage<'w>>,
+    num_threads: usize,
+    // Started on the first parallel run
+    pool: Option<Pool>,
+    disabled: Vec<&'static str>,
+}
+
+impl Default for Schedule<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'w> Schedule<'w> {
+    pub fn new() -> Self {
+        Schedule {
+            stages: Vec::new(),
+            num_threads: 1,
+            pool: None,
+            disabled: Vec::new(),
+        }
+    }
+
+    /// Sets how many threads run the systems, including the one calling [`Schedule::run`]. The
+    /// default of 1 runs everything on the calling thread. The other threads are started by the
+    /// first run that needs them and kept until the schedule is dropped or the number changes.
+    /// Systems with [`Commands`](crate::Commands) always run on the calling thread.
+    pub fn set_num_threads(&mut self, num_threads: usize) -> &mut Self {
+        if num_threads != self.num_threads {
+            self.pool = None;
+        }
+        self.num_threads = num_threads;
+        self
+    }
+
+    /// Appends a stage that runs after the existing ones
+    ///
+    /// # Panics
+    ///
+    /// If there already is a stage with the name
+    pub fn add_stage(&mut self, name: &'static str) -> &mut Self {
+        if self.stages.iter().any(|stage| stage.name == name) {
+            panic!("stage {} already exists", name);
+        }
+        self.stages.push(Stage {
+            name,
+            systems: Vec::new(),
+            plan: None,
+        });
+        self
+    }
+
+    /// The parameters must be [`ParallelParam`]s, i.e. the components and resources the system
+    /// reads must be `Sync` and the ones it writes `Send`
+    ///
+    /// # Panics
+    ///
+    /// If the stage does not exist
+    pub fn add_system<Params: ParallelParam + 'w>(
+        &mut self,
+        stage: &'static str,
+        system: impl System<'w, Params> + Send + 'w,
+    ) -> &mut ScheduledSystem<'w> {
+        let Some(stage) = self.stages.iter_mut().find(|s| s.name == stage) else {
+            panic!("stage {} does not exist", stage);
+        };
+
+        let mut access = Access::default();
+        system.access(&mut access);
+        stage.plan = None;
+        stage.systems.push(ScheduledSystem {
+            name: name_of(&system),
+            system: Box::new(Erased {
+                f: system,
+                marker: PhantomData,
+            }),
+            access,
+            labels: Vec::new(),
+            before: Vec::new(),
+            after: Vec::new(),
+            conditions: Vec::new(),
+            last_run: 0,
+        });
+        stage.systems.last_mut().unwrap()
+    }
+
+    /// Skips the systems with the label until it is enabled again. Systems sharing a label can be
+    /// turned off as a set this way. Their constraints still apply to the other systems.
+    ///
+    /// # Panics
+    ///
+    /// If no system has the label
+    pub fn disable(&mut self, label: &'static str) -> &mut Self {
+        self.assert_label(label);
+        if !self.disabled.contains(&label) {
+            self.disabled.push(label);
+        }
+        self
+    }
+
+    /// Runs the systems with the label again, unless another of their labels is disabled
+    ///
+    /// # Panics
+    ///
+    /// If no system has the label
+    pub fn enable(&mut self, label: &'static str) -> &mut Self {
+        self.assert_label(label);
+        self.disabled.retain(|disabled| *disabled != label);
+        self
+    }
+
+    pub fn is_enabled(&self, label: &'static str) -> bool {
+        !self.disabled.contains(&label)
+    }
+
+    fn assert_label(&self, label: &'static str) {
+        let mut systems = self.stages.iter().flat_map(|stage| &stage.systems);
+        if !systems.any(|system| system.labels.contains(&label)) {
+            panic!("{}", ScheduleError::UnknownLabel(label));
+        }
+    }
+
+    /// Checks the constraints and computes the order of the stages that changed since the last
+    /// call. Done by [`Schedule::run`] as well, this only reports errors earlier.
+    pub fn build(&mut self) -> Result<(), ScheduleError> {
+        let systems = || self.stages.iter().flat_map(|stage| &stage.systems);
+        for stage in &self.stages {
+            for system in &stage.systems {
+                for label in system.before.iter().chain(&system.after) {
+                    if stage
+                        .systems
+                        .iter()
+                        .any(|other| other.labels.contains(label))
+                    {
+                        continue;
+                    }
+                    if !systems().any(|other| other.labels.contains(label)) {
+                        return Err(ScheduleError::UnknownLabel(label));
+                    }
+                    return Err(ScheduleError::LabelInOtherStage {
+                        label,
+                        stage: stage.name,
+                    });
+                }
+            }
+        }
+
+        for stage in &mut self.stages {
+            if stage.plan.is_none() {
+                stage.plan = Some(stage.plan()?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs every stage once
+    ///
+    /// # Panics
+    ///
+    /// On the errors [`Schedule::try_run`] returns
+    pub fn run(&mut self, world: &'w World) {
+        if let Err(err) = self.try_run(world) {
+            panic!("{}", err);
+        }
+    }
+
+    /// Same as [`Schedule::run`] but returns an error instead of panicking if the constraints
+    /// cannot be satisfied or a system conflicts with an outstanding borrow, in which case no more
+    /// systems of the stage are started
+    pub fn try_run(&mut self, world: &'w World) -> Result<(), ScheduleError> {
+        self.build()?;
+
+        for stage in &mut self.stages {
+            let _deferring = world.defer();
+
+            // Ticks are handed out in the serial order however the systems end up running, `None`
+            // for the systems that are skipped
+            let plan = stage.plan.as_ref().unwrap();
+            let ticks: Vec<_> = plan
+                .order
+                .iter()
+                .map(|index| {
+                    let system = &mut stage.systems[*index];
+                    if system.labels.iter().any(|l| self.disabled.contains(l)) {
+                        return None;
+                    }
+                    let mut run = true;
+                    for condition in &mut system.conditions {
+                        run &= condition(world);
+                    }
+                    run.then(|| world.advance_tick(system.last_run))
+                })
+                .collect();
+
+            if self.num_threads <= 1 || plan.order.len() <= 1 {
+                for (pos, index) in plan.order.iter().enumerate() {
+                    if let Some(ticks) = ticks[pos] {
+                        unsafe { run_system(world, &mut stage.systems[*index], ticks)? };
+                    }
+                }
+            } else {
+                let pool = self
+                    .pool
+                    .get_or_insert_with(|| Pool::new(self.num_threads - 1));
+                run_parallel(world, plan, &mut stage.systems, &ticks, pool)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Borrows what the system accesses for the duration of its run
+///
+/// # Safety
+///
+/// No other thread may run `system` at the same time
+unsafe fn run_system<'w>(
+    world: &'w World,
+    system: &mut ScheduledSystem<'w>,
+    ticks: SystemTicks,
+) -> Result<(), BorrowError> {
+    struct Borrowed<'a>(&'a World, &'a Access);
+    impl Drop for Borrowed<'_> {
+        fn drop(&mut self) {
+            self.0.inner().borrows.release(self.1);
+        }
+    }
+
+    world.inner().borrows.acquire(&system.access)?;
+    let _borrowed = Borrowed(world, &system.access);
+    system.last_run = ticks.this_run;
+    unsafe { system.system.run(world, ticks) };
+    Ok(())
+}
+
+struct Progress {
+    // Positions whose dependencies have all run
+    ready: Vec<usize>,
+    num_deps: Vec<usize>,
+    num_done: usize,
+    error: Option<BorrowError>,
+    // Set when a system fails or panics, the workers then stop picking up systems
+    aborted: bool,
+}
+
+struct Shared<'a, 'w> {
+    progress: Mutex<Progress>,
+    changed: Condvar,
+    systems: *mut ScheduledSystem<'w>,
+    marker: PhantomData<&'a mut [ScheduledSystem<'w>]>,
+}
+
+// Every system is run by one worker only, once all the systems it conflicts with are done
+unsafe impl Send for Shared<'_, '_> {}
+unsafe impl Sync for Shared<'_, '_> {}
+
+/// Runs the systems of a stage on the pool and the calling thread. A system is started once every
+/// earlier system it depends on is done, the earliest of the ready ones first.
+fn run_parallel<'w>(
+    world: &'w World,
+    plan: &Plan,
+    systems: &mut [ScheduledSystem<'w>],
+    ticks: &[Option<SystemTicks>],
+    pool: &Pool,
+) -> Result<(), BorrowError> {
+    let n = plan.order.len();
+    let shared = Shared {
+        progress: Mutex::new(Progress {
+            ready: (0..n).filter(|pos| plan.num_deps[*pos] == 0).collect(),
+            num_deps: plan.num_deps.clone(),
+            num_done: 0,
+            error: None,
+            aborted: false,
+        }),
+        changed: Condvar::new(),
+        systems: systems.as_mut_ptr(),
+        marker: PhantomData,
+    };
+
+    // Captured as a whole, the fields on their own are not `Sync`
+    let shared = &shared;
+    let caller = thread::current().id();
+    let worker = || {
+        struct AbortOnPanic<'a>(&'a Mutex<Progress>, &'a Condvar);
+        impl Drop for AbortOnPanic<'_> {
+            fn drop(&mut self) {
+                if thread::panicking() {
+                    let mut progress = self.0.lock().unwrap_or_else(|err| err.into_inner());
+                    progress.aborted = true;
+                    self.1.notify_all();
+                }
+            }
+        }
+
+        let on_caller = thread::current().id() == caller;
+        let mut progress = shared.progress.lock().unwrap();
+        while !progress.aborted && progress.num_done < n {
+            let next = (0..progress.ready.len())
+                .filter(|i| on_caller || !plan.on_caller[progress.ready[*i]])
+                .min_by_key(|i| progress.ready[*i]);
+            let Some(next) = next else {
+                progress = shared.changed.wait(progress).unwrap();
+                continue;
+            };
+            let pos = progress.ready.swap_remove(next);
+            drop(progress);
+
+            let abort = AbortOnPanic(&shared.progress, &shared.changed);
+            let system = unsafe { &mut *shared.systems.add(plan.order[pos]) };
+            let result = match ticks[pos] {
+                Some(ticks) => unsafe { run_system(world, system, ticks) },
+                None => Ok(()),
+            };
+            drop(abort);
+
+            progress = shared.progress.lock().unwrap();
+            match result {
+                Ok(()) => {
+                    progress.num_done += 1;
+                    for dependent in &plan.dependents[pos] {
+                        progress.num_deps[*dependent] -= 1;
+                        if progress.num_deps[*dependent] == 0 {
+                            progress.ready.push(*dependent);
+                        }
+                    }
+                }
+                Err(err) => {
+                    progress.error.get_or_insert(err);
+                    progress.aborted = true;
+                }
+            }
+            shared.changed.notify_all();
+        }
+    };
+
+    pool.run(&worker);
+
+    let error = shared.progress.lock().unwrap().error;
+    match error {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+/// Threads that are started once and then run the stages of every [`Schedule::run`]
+struct Pool {
+    shared: Arc<PoolShared>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+struct PoolShared {
+    state: Mutex<PoolState>,
+    changed: Condvar,
+}
+
+struct PoolState {
+    job: Option<Job>,
+    // Bumped for every job, so that every thread runs each job once
+    generation: u64,
+    // The threads still running the job
+    running: usize,
+    panic: Option<Box<dyn Any + Send>>,
+    stop: bool,
+}
+
+/// A job borrowed from `Pool::run`, which does not return before every thread is done with it
+struct Job(*const (dyn Fn() + Sync + 'static));
+
+unsafe impl Send for Job {}
+
+impl Pool {
+    /// Starts `num_threads` threads, not counting the calling one
+    fn new(num_threads: usize) -> Self {
+        let shared = Arc::new(PoolShared {
+            state: Mutex::new(PoolState {
+                job: None,
+                generation: 0,
+                running: 0,
+                panic: None,
+                stop: false,
+            }),
+            changed: Condvar::new(),
+        });
+        let threads = (0..num_threads)
+            .map(|_| {
+                let shared = shared.clone();
+                thread::spawn(move || shared.work())
+            })
+            .collect();
+        Pool { shared, threads }
+    }
+
+    /// Runs `job` on every thread of the pool and the calling one, and waits until all of them
+    /// are done. A panic on one of the threads is resumed on the calling one.
+    fn run(&self, job: &(dyn Fn() + Sync)) {
+        struct WaitForThreads<'a>(&'a PoolShared);
+        impl Drop for WaitForThreads<'_> {
+            fn drop(&mut self) {
+                let state = self.0.state.lock().unwrap_or_else(|err| err.into_inner());
+                let mut state = self
+                    .0
+                    .changed
+                    .wait_while(state, |state| state.running > 0)
+                    .unwrap_or_else(|err| err.into_inner());
+                state.job = None;
+            }
+        }
+
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            // The threads only use the job until they decrement `running`, which is waited for
+            // below even if `job` panics on the calling thread
+            let job = unsafe {
+                mem::transmute::<*const (dyn Fn() + Sync + '_), *const (dyn Fn() + Sync + 'static)>(
+                    job,
+                )
+            };
+            state.job = Some(Job(job));
+            state.panic = None;
+            state.generation += 1;
+            state.running = self.threads.len();
+            self.shared.changed.notify_all();
+        }
+
+        let wait = WaitForThreads(&self.shared);
+        job();
+        drop(wait);
+
+        let panic = self.shared.state.lock().unwrap().panic.take();
+        if let Some(panic) = panic {
+            panic::resume_unwind(panic);
+        }
+    }
+}
+
+impl PoolShared {
+    fn work(&self) {
+        let mut generation = 0;
+        loop {
+            let state = self.state.lock().unwrap();
+            let state = self
+                .changed
+                .wait_while(state, |state| !state.stop && state.generation == generation)
+                .unwrap();
+            if state.stop {
+                return;
+            }
+            generation = state.generation;
+            let job = state.job.as_ref().unwrap().0;
+            drop(state);
+
+            let result = panic::catch_unwind(AssertUnwindSafe(|| unsafe { (*job)() }));
+
+            let mut state = self.state.lock().unwrap();
+            if let Err(panic) = result {
+                state.panic.get_or_insert(panic);
+            }
+            state.running -= 1;
+            self.changed.notify_all();
+        }
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().stop = true;
+        self.shared.changed.notify_all();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// A run condition that is true when the resource `R` was inserted or borrowed mutably since the
+/// condition was last checked, see [`World::is_resource_changed`]
+/// ```ignore
+///   schedule
+///       .add_system("update", rebuild_key_map)
+///       .run_if(resource_changed::<Settings>());
+/// ```
+pub fn resource_changed<R: Send + Sync + 'static>() -> impl FnMut(&World) -> bool + Send {
+    let mut last_check = LastRun::new();
+    move |world| world.is_resource_changed::<R>(&mut last_check)
+}
+
+fn name_of<T>(_: &T) -> &'static str {
+    type_name::<T>()
+}
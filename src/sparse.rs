@@ -0,0 +1,121 @@
+use std::cell::Cell;
+
+use crate::{
+    change::ComponentTicks,
+    component::{Component, Metadata},
+    error::AllocError,
+    removed::RemovedLog,
+    table::Column,
+    Entity,
+};
+
+/// Storage for a component declared with `#[component(storage = "sparse")]`. Values are packed
+/// in a dense column and looked up through a sparse array indexed by entity index, so adding or
+/// removing one never moves the entity to another table.
+///
+/// Entries are keyed by index only, the world removes them on despawn before the slot is reused.
+pub(crate) struct SparseSet {
+    // Indexed by entity index: the row of the entity's value plus one, 0 if it has none
+    sparse: Vec<u32>,
+    dense: Column,
+    // The entity index of every row, to patch `sparse` when a row is swap-removed
+    entities: Vec<u32>,
+}
+
+impl SparseSet {
+    pub fn new(metadata: Metadata) -> Self {
+        SparseSet {
+            sparse: Vec::new(),
+            dense: Column::from_metadata(metadata),
+            entities: Vec::new(),
+        }
+    }
+
+    #[inline(always)]
+    fn row(&self, entity: Entity) -> Option<usize> {
+        match self.sparse.get(*entity as usize) {
+            Some(0) | None => None,
+            Some(row) => Some(*row as usize - 1),
+        }
+    }
+
+    #[inline(always)]
+    pub fn contains(&self, entity: Entity) -> bool {
+        self.row(entity).is_some()
+    }
+
+    #[inline(always)]
+    pub unsafe fn get<T: Component + 'static>(&self, entity: Entity) -> Option<&T> {
+        self.row(entity).map(|row| self.dense.read::<T>(row))
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    #[inline(always)]
+    pub unsafe fn get_mut<T: Component + 'static>(&self, entity: Entity) -> Option<&mut T> {
+        self.row(entity).map(|row| self.dense.read_mut::<T>(row))
+    }
+
+    #[inline(always)]
+    pub fn ticks(&self, entity: Entity) -> Option<&Cell<ComponentTicks>> {
+        self.row(entity).map(|row| unsafe { self.dense.ticks(row) })
+    }
+
+    /// Makes room for `additional` more values, for entities with an index up to `max_index`
+    pub fn try_reserve(&mut self, additional: usize, max_index: usize) -> Result<(), AllocError> {
+        self.dense.try_reserve(self.entities.len(), additional)?;
+        self.entities.try_reserve(additional)?;
+        self.sparse
+            .try_reserve((max_index + 1).saturating_sub(self.sparse.len()))?;
+        Ok(())
+    }
+
+    /// Copies `val` in for the entity, which must not have a value yet, as added at `tick`. The
+    /// caller is responsible for not dropping `val` afterwards.
+    pub unsafe fn insert_any(&mut self, entity: Entity, val: &dyn Component, tick: u32) {
+        let row = self.entities.len();
+        self.dense.write_any(row, val, tick);
+        self.entities.push(*entity);
+
+        let index = *entity as usize;
+        if index >= self.sparse.len() {
+            self.sparse.resize(index + 1, 0);
+        }
+        self.sparse[index] = row as u32 + 1;
+    }
+
+    /// Drops the entity's value. Returns false if it had none.
+    pub fn remove(&mut self, entity: Entity) -> bool {
+        self.remove_into(entity, None)
+    }
+
+    /// Same as `remove`, but hands the value to `log` if there is one
+    pub fn remove_into(&mut self, entity: Entity, log: Option<&mut RemovedLog>) -> bool {
+        let Some(row) = self.row(entity) else {
+            return false;
+        };
+
+        let last = self.entities.len() - 1;
+        unsafe {
+            match log {
+                Some(log) => log.push(entity, &mut self.dense, row),
+                None => self.dense.drop_item(row),
+            }
+            self.dense.move_item(last, row);
+        }
+        self.entities.swap_remove(row);
+        if row != last {
+            self.sparse[self.entities[row] as usize] = row as u32 + 1;
+        }
+        self.sparse[*entity as usize] = 0;
+
+        true
+    }
+}
+
+impl Drop for SparseSet {
+    fn drop(&mut self) {
+        for row in 0..self.entities.len() {
+            unsafe { self.dense.drop_item(row) };
+        }
+    }
+}
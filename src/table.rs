@@ -1,52 +1,143 @@
 use core::panic;
 use std::{
-    collections::BTreeSet,
+    alloc::Layout,
+    cell::Cell,
+    collections::HashMap,
     mem,
-    ptr::{drop_in_place, null_mut},
+    ptr::{null_mut, without_provenance_mut},
+    sync::Arc,
 };
 
-use crate::component::{Component, ComponentId, Metadata};
+use crate::{
+    archetype::Archetype,
+    change::ComponentTicks,
+    component::{Component, ComponentId, Metadata},
+    error::AllocError,
+    Entity,
+};
 
+/// Zero-sized components never allocate: their data pointer is dangling but aligned, which is
+/// all a reference to a zero-sized value needs, and the capacity is unbounded.
+///
+/// Every written row also has change ticks, which mutable borrows update through `&Column`.
 pub(crate) struct Column {
     data: *mut u8,
     item_size: usize,
     item_align: usize,
+    drop: Option<unsafe fn(*mut u8)>,
     cap: usize,
+    ticks: Vec<Cell<ComponentTicks>>,
 }
 
 impl Column {
-    pub fn new(item_size: usize, item_align: usize) -> Self {
+    pub fn new(item_layout: Layout, drop: Option<unsafe fn(*mut u8)>) -> Self {
+        let (item_size, item_align) = (item_layout.size(), item_layout.align());
+        if item_size == 0 {
+            return Column {
+                data: without_provenance_mut(item_align),
+                item_size,
+                item_align,
+                drop,
+                cap: usize::MAX,
+                ticks: Vec::new(),
+            };
+        }
+
         Column {
             data: null_mut(),
             item_size,
             item_align,
+            drop,
             cap: 0,
+            ticks: Vec::new(),
         }
     }
 
+    pub fn from_metadata(metadata: Metadata) -> Self {
+        let layout = Layout::from_size_align(metadata.size(), metadata.align()).unwrap();
+        Column::new(layout, metadata.drop_fn())
+    }
+
+    /// The layout of a single item
+    #[inline(always)]
+    pub fn item_layout(&self) -> Layout {
+        unsafe { Layout::from_size_align_unchecked(self.item_size, self.item_align) }
+    }
+
+    fn buffer_layout(&self, cap: usize) -> Result<Layout, AllocError> {
+        self.item_size
+            .checked_mul(cap)
+            .and_then(|size| Layout::from_size_align(size, self.item_align).ok())
+            .ok_or(AllocError::CapacityOverflow)
+    }
+
+    /// Grows the buffer to exactly `cap` items, leaving it untouched on failure
+    fn try_grow_to(&mut self, cap: usize) -> Result<(), AllocError> {
+        if cap <= self.cap {
+            return Ok(());
+        }
+
+        let layout = self.buffer_layout(cap)?;
+        let data = unsafe {
+            if self.data.is_null() {
+                std::alloc::alloc(layout)
+            } else {
+                std::alloc::realloc(self.data, self.buffer_layout(self.cap)?, layout.size())
+            }
+        };
+        if data.is_null() {
+            return Err(AllocError::OutOfMemory(layout));
+        }
+
+        self.data = data;
+        self.cap = cap;
+        Ok(())
+    }
+
+    /// # Panics
+    ///
+    /// If the allocation fails, use `try_reserve` beforehand to handle that
     unsafe fn grow(&mut self, idx: usize) {
         const INITIAL_CAP: usize = 1;
 
         if idx >= self.cap {
-            let new_cap = if idx == 0 { INITIAL_CAP } else { idx * 2 };
-            if self.data.is_null() {
-                let layout =
-                    std::alloc::Layout::from_size_align(self.item_size * new_cap, self.item_align)
-                        .unwrap();
-                self.data = std::alloc::alloc(layout);
+            let new_cap = if idx == 0 {
+                INITIAL_CAP
             } else {
-                let layout =
-                    std::alloc::Layout::from_size_align(self.item_size * self.cap, self.item_align)
-                        .unwrap();
-                self.data = std::alloc::realloc(self.data, layout, self.item_size * new_cap);
-            }
-            if self.data.is_null() {
-                todo!()
+                idx.saturating_mul(2)
+            };
+            if let Err(err) = self.try_grow_to(new_cap) {
+                panic!("{}", err);
             }
-            self.cap = new_cap;
         }
     }
 
+    /// Makes room for `additional` more items after the first `len`
+    pub fn try_reserve(&mut self, len: usize, additional: usize) -> Result<(), AllocError> {
+        let needed = len
+            .checked_add(additional)
+            .ok_or(AllocError::CapacityOverflow)?;
+        self.ticks
+            .try_reserve(needed.saturating_sub(self.ticks.len()))?;
+        if needed <= self.cap {
+            return Ok(());
+        }
+        self.try_grow_to(needed.max(self.cap.saturating_mul(2)))
+    }
+
+    fn set_ticks(&mut self, idx: usize, ticks: ComponentTicks) {
+        if idx >= self.ticks.len() {
+            self.ticks.resize(idx + 1, Cell::default());
+        }
+        self.ticks[idx].set(ticks);
+    }
+
+    /// The change ticks of the item at `idx`, which must have been written
+    #[inline(always)]
+    pub unsafe fn ticks(&self, idx: usize) -> &Cell<ComponentTicks> {
+        self.ticks.get_unchecked(idx)
+    }
+
     #[inline(always)]
     pub unsafe fn read<T: Component + 'static>(&self, idx: usize) -> &T {
         &*self.data.cast::<T>().add(idx)
@@ -58,23 +149,43 @@ impl Column {
         &mut *self.data.cast::<T>().add(idx)
     }
 
+    /// The first `len` items, which must all have been written
+    #[inline(always)]
+    pub unsafe fn slice<T: Component + 'static>(&self, len: usize) -> &[T] {
+        std::slice::from_raw_parts(self.data.cast::<T>(), len)
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    #[inline(always)]
+    pub unsafe fn slice_mut<T: Component + 'static>(&self, len: usize) -> &mut [T] {
+        std::slice::from_raw_parts_mut(self.data.cast::<T>(), len)
+    }
+
+    /// Writes an item that was added at tick 0, i.e. that never counts as added or changed, which
+    /// is what the entity column wants
     #[inline(always)]
     pub unsafe fn write<T: Component + 'static>(&mut self, idx: usize, val: T) {
         self.grow(idx);
+        self.set_ticks(idx, ComponentTicks::default());
         self.data.cast::<T>().add(idx).write(val);
     }
 
+    /// Writes an item that was added at `tick`
     #[inline(always)]
-    pub unsafe fn write_any(&mut self, idx: usize, val: &dyn Component) {
+    pub unsafe fn write_any(&mut self, idx: usize, val: &dyn Component, tick: u32) {
         self.grow(idx);
+        self.set_ticks(idx, ComponentTicks::new(tick));
         self.data
             .add(self.item_size * idx)
             .copy_from_nonoverlapping(mem::transmute_copy(&val), val.metadata().size());
     }
 
+    /// Runs the destructor of the item at `idx`, leaving the slot uninitialized
     #[inline(always)]
-    pub unsafe fn destroy<T: Component + 'static>(&self, idx: usize) {
-        drop_in_place::<T>(self.data.add(self.item_size * idx).cast());
+    pub unsafe fn drop_item(&mut self, idx: usize) {
+        if let Some(drop) = self.drop {
+            drop(self.data.add(self.item_size * idx));
+        }
     }
 
     #[inline(always)]
@@ -84,9 +195,13 @@ impl Column {
         src_idx: usize,
         dst_idx: usize,
     ) {
-        if src.item_size != dst.item_size {
+        if src.item_layout() != dst.item_layout() {
             panic!()
         }
+        dst.set_ticks(dst_idx, src.ticks(src_idx).get());
+        if dst.item_size == 0 {
+            return;
+        }
 
         dst.grow(dst_idx);
         let ptr_src = src.data.add(src.item_size * src_idx);
@@ -95,105 +210,162 @@ impl Column {
         ptr_dst.copy_from_nonoverlapping(ptr_src, dst.item_size);
     }
 
+    /// Moves the item at `src_idx` over the one at `dst_idx` without dropping either
     #[inline(always)]
-    pub unsafe fn get_component_size(&self) -> usize {
-        self.item_size
+    pub unsafe fn move_item(&mut self, src_idx: usize, dst_idx: usize) {
+        if src_idx != dst_idx {
+            self.ticks[dst_idx].set(self.ticks[src_idx].get());
+            let ptr_src = self.data.add(self.item_size * src_idx);
+            let ptr_dst = self.data.add(self.item_size * dst_idx);
+            ptr_dst.copy_from_nonoverlapping(ptr_src, self.item_size);
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_drop_fn(&self) -> Option<unsafe fn(*mut u8)> {
+        self.drop
+    }
+
+    #[inline(always)]
+    pub fn is_zero_sized(&self) -> bool {
+        self.item_size == 0
     }
 }
 
+/// Frees the buffer only, the items are dropped by the owning `Table` which knows which rows are live
 impl Drop for Column {
     fn drop(&mut self) {
-        let layout =
-            std::alloc::Layout::from_size_align(self.item_size * self.cap, self.item_align)
-                .unwrap();
+        if self.data.is_null() || self.item_size == 0 {
+            return;
+        }
+
+        let layout = Layout::from_size_align(self.item_size * self.cap, self.item_align).unwrap();
         unsafe { std::alloc::dealloc(self.data, layout) };
     }
 }
 
+pub(crate) type TableId = usize;
+
+/// A cached transition to the table an entity moves to when one component is added or removed
+#[derive(Clone)]
+pub(crate) struct Edge {
+    pub target: TableId,
+    // The columns both tables have except zero-sized ones, i.e. the ones to copy over
+    pub shared: Arc<[ComponentId]>,
+    // The zero-sized columns both tables have, whose change ticks still move with the row
+    pub zero_sized: Arc<[ComponentId]>,
+}
+
 pub(crate) struct Table {
+    archetype: Archetype,
+    add_edges: HashMap<ComponentId, Edge>,
+    remove_edges: HashMap<ComponentId, Edge>,
+    // Indexed by component id, grown on demand
     cols: Vec<Option<Column>>,
-    end_index: usize,
-    free_indices: BTreeSet<usize>,
+    // Rows are kept dense, every row below `len` holds a live entity
+    len: usize,
 }
 
 impl Table {
-    pub fn new() -> Self {
+    pub fn new(archetype: Archetype) -> Self {
         Table {
-            cols: (0..128).map(|_| None).collect(), // TODO resize on demand
-            end_index: 0,
-            free_indices: BTreeSet::new(),
+            archetype,
+            add_edges: HashMap::new(),
+            remove_edges: HashMap::new(),
+            cols: Vec::new(),
+            len: 0,
         }
     }
 
+    pub fn archetype(&self) -> &Archetype {
+        &self.archetype
+    }
+
     pub fn len(&self) -> usize {
-        self.end_index
+        self.len
     }
 
-    pub fn reserve_index(&mut self) -> usize {
-        if let Some(index) = self.free_indices.pop_first() {
-            index
-        } else {
-            let index = self.end_index;
-            self.end_index += 1;
-            index
-        }
+    pub fn add_edge(&self, id: ComponentId) -> Option<&Edge> {
+        self.add_edges.get(&id)
     }
 
-    pub fn free_index(&mut self, index: usize) {
-        self.free_indices.insert(index);
+    pub fn set_add_edge(&mut self, id: ComponentId, edge: Edge) {
+        self.add_edges.insert(id, edge);
     }
 
-    #[allow(clippy::mut_from_ref)]
-    pub unsafe fn read_mut<T: Component + 'static>(&mut self, entity_index: usize) -> &mut T {
-        self.cols
-            .get_mut(T::metadata_static().id().0 as usize)
-            .unwrap()
-            .as_mut()
-            .unwrap()
-            .read_mut::<T>(entity_index)
+    pub fn remove_edge(&self, id: ComponentId) -> Option<&Edge> {
+        self.remove_edges.get(&id)
     }
 
-    pub unsafe fn try_read<T: Component + 'static>(&self, entity_index: usize) -> Option<&T> {
-        self.cols
-            .get(T::metadata_static().id().0 as usize)
-            .unwrap()
-            .as_ref()
-            .map(|col| col.read::<T>(entity_index))
+    pub fn set_remove_edge(&mut self, id: ComponentId, edge: Edge) {
+        self.remove_edges.insert(id, edge);
     }
 
-    #[allow(clippy::mut_from_ref)]
-    pub unsafe fn try_read_mut<T: Component + 'static>(
+    /// Makes room for `additional` more rows in every column, creating the entity column and the
+    /// columns in `metadata` the table does not have yet
+    pub fn try_reserve(
         &mut self,
-        entity_index: usize,
-    ) -> Option<&mut T> {
-        self.cols
-            .get_mut(T::metadata_static().id().0 as usize)
-            .unwrap()
-            .as_mut()
-            .map(|col| col.read_mut::<T>(entity_index))
+        metadata: &[Metadata],
+        additional: usize,
+    ) -> Result<(), AllocError> {
+        self.column_or_insert(Entity::metadata_static());
+        // Sparse components live in the world's sparse sets
+        for metadata in metadata.iter().filter(|metadata| !metadata.is_sparse()) {
+            self.column_or_insert(*metadata);
+        }
+        let len = self.len;
+        for col in self.cols.iter_mut().flatten() {
+            col.try_reserve(len, additional)?;
+        }
+        Ok(())
     }
 
-    pub unsafe fn write<T: Component + 'static>(&mut self, entity_index: usize, val: T) {
-        if self
-            .cols
-            .get(T::metadata_static().id().0 as usize)
-            .unwrap()
-            .is_none()
-        {
-            self.cols
-                .get_mut(T::metadata_static().id().0 as usize)
-                .unwrap()
-                .replace(Column::new(
-                    T::metadata_static().size(),
-                    T::metadata_static().align(),
-                ));
+    /// Appends a row, the caller has to write every column of it
+    pub fn reserve_index(&mut self) -> usize {
+        let index = self.len;
+        self.len += 1;
+        index
+    }
+
+    /// Fills the row with the last one and shrinks the table. The items in the row must have been
+    /// dropped or moved out already. Returns the entity that was moved into the row, if any.
+    pub unsafe fn swap_remove(&mut self, index: usize) -> Option<Entity> {
+        let last = self.len - 1;
+        for col in self.cols.iter_mut().flatten() {
+            col.move_item(last, index);
+        }
+        self.len = last;
+
+        if index != last {
+            Some(*self.get_column::<Entity>()?.read::<Entity>(index))
+        } else {
+            None
+        }
+    }
+
+    /// Runs the destructors of every component in the row without freeing it
+    pub unsafe fn drop_row(&mut self, index: usize) {
+        for col in self.cols.iter_mut().flatten() {
+            col.drop_item(index);
         }
+    }
+
+    pub fn columns_mut(&mut self) -> impl Iterator<Item = (ComponentId, &mut Column)> {
         self.cols
-            .get_mut(T::metadata_static().id().0 as usize)
-            .as_mut()
-            .unwrap()
-            .as_mut()
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(id, col)| Some((ComponentId(id as u32), col.as_mut()?)))
+    }
+
+    #[allow(dead_code)]
+    pub unsafe fn read_mut<T: Component + 'static>(&mut self, entity_index: usize) -> &mut T {
+        self.get_column_by_id_mut(T::metadata_static().id())
             .unwrap()
+            .read_mut::<T>(entity_index)
+    }
+
+    pub unsafe fn write<T: Component + 'static>(&mut self, entity_index: usize, val: T) {
+        self.column_or_insert(T::metadata_static())
             .write(entity_index, val);
     }
 
@@ -202,42 +374,39 @@ impl Table {
         metadata: Metadata,
         entity_index: usize,
         val: &dyn Component,
+        tick: u32,
     ) {
-        if self.cols.get(metadata.id().0 as usize).unwrap().is_none() {
-            self.cols
-                .get_mut(metadata.id().0 as usize)
-                .unwrap()
-                .replace(Column::new(metadata.size(), metadata.align()));
+        self.column_or_insert(metadata)
+            .write_any(entity_index, val, tick);
+    }
+
+    fn column_or_insert(&mut self, metadata: Metadata) -> &mut Column {
+        let slot = self.slot_mut(metadata.id());
+        if slot.is_none() {
+            slot.replace(Column::from_metadata(metadata));
         }
-        self.cols
-            .get_mut(metadata.id().0 as usize)
-            .as_mut()
-            .unwrap()
-            .as_mut()
-            .unwrap()
-            .write_any(entity_index, val);
+        unsafe { slot.as_mut().unwrap_unchecked() }
     }
 
-    pub unsafe fn destroy<T: Component + 'static>(&self, entity_index: usize) {
-        if let Some(col) = self
-            .cols
-            .get(T::metadata_static().id().0 as usize)
-            .unwrap()
-            .as_ref()
-        {
-            col.destroy::<T>(entity_index)
+    fn slot_mut(&mut self, id: ComponentId) -> &mut Option<Column> {
+        let id = id.0 as usize;
+        if id >= self.cols.len() {
+            self.cols.resize_with(id + 1, || None);
         }
+        unsafe { self.cols.get_unchecked_mut(id) }
     }
 
-    pub unsafe fn add_column_by_id(&mut self, id: ComponentId, size: usize, align: usize) {
-        self.cols
-            .get_mut(id.0 as usize)
-            .unwrap()
-            .replace(Column::new(size, align));
+    pub unsafe fn add_column_by_id(
+        &mut self,
+        id: ComponentId,
+        item_layout: Layout,
+        drop: Option<unsafe fn(*mut u8)>,
+    ) {
+        self.slot_mut(id).replace(Column::new(item_layout, drop));
     }
 
     pub unsafe fn get_column_by_id(&self, id: ComponentId) -> Option<&Column> {
-        self.cols.get(id.0 as usize).unwrap().as_ref()
+        self.cols.get(id.0 as usize).and_then(Option::as_ref)
     }
 
     pub unsafe fn get_column<T: Component + 'static>(&self) -> Option<&Column> {
@@ -245,21 +414,24 @@ impl Table {
     }
 
     pub unsafe fn get_column_by_id_mut(&mut self, id: ComponentId) -> Option<&mut Column> {
-        self.cols.get_mut(id.0 as usize).unwrap().as_mut()
+        self.cols.get_mut(id.0 as usize).and_then(Option::as_mut)
     }
+}
 
-    pub fn has_component<T: Component + 'static>(&self) -> bool {
-        self.cols
-            .get(T::metadata_static().id().0 as usize)
-            .unwrap()
-            .is_some()
+impl Drop for Table {
+    fn drop(&mut self) {
+        for index in 0..self.len {
+            unsafe { self.drop_row(index) };
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::{cell::Cell, rc::Rc};
+
     use super::{Column, Table};
-    use crate::{self as ecs, component, Component};
+    use crate::{self as ecs, archetype::Archetype, component, error::AllocError, Component};
 
     #[component]
     struct A(u32);
@@ -267,9 +439,22 @@ mod tests {
     #[component]
     struct B(&'static str);
 
+    #[component]
+    struct D(Rc<Cell<u32>>);
+
+    #[component]
+    #[repr(align(64))]
+    struct Tag;
+
+    impl Drop for D {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
     #[test]
     fn column_write_read() {
-        let mut col = Column::new(A::metadata_static().size(), A::metadata_static().align());
+        let mut col = Column::from_metadata(A::metadata_static());
         unsafe {
             col.write(0, A(0));
             col.write(1, A(1));
@@ -283,7 +468,7 @@ mod tests {
 
     #[test]
     fn table_write_read() {
-        let mut table = Table::new();
+        let mut table = Table::new(Archetype::new());
         unsafe {
             table.write(0, A(100u32));
             table.write(0, B("100"));
@@ -300,4 +485,63 @@ mod tests {
             assert_eq!(table.read_mut::<B>(100000).0, "102");
         }
     }
+
+    #[test]
+    fn zero_sized_column() {
+        let mut col = Column::from_metadata(Tag::metadata_static());
+        assert!(col.is_zero_sized());
+        unsafe {
+            for i in 0..1000 {
+                col.write(i, Tag);
+            }
+            let tag: *const Tag = col.read::<Tag>(999);
+            assert!(tag.is_aligned());
+
+            let mut other = Column::from_metadata(Tag::metadata_static());
+            Column::copy_item_from_column(&col, &mut other, 999, 12345);
+            assert!(std::ptr::eq(other.read::<Tag>(12345), tag));
+        }
+        assert!(col.data.addr() == 64 && col.cap == usize::MAX);
+    }
+
+    #[test]
+    fn table_try_reserve() {
+        let mut table = Table::new(Archetype::new());
+        table.try_reserve(&[A::metadata_static()], 10).unwrap();
+        let data = unsafe { table.get_column::<A>().unwrap().data };
+
+        unsafe {
+            for i in 0..10 {
+                let index = table.reserve_index();
+                table.write(index, A(i));
+            }
+            assert_eq!(table.get_column::<A>().unwrap().data, data);
+        }
+
+        assert_eq!(
+            table.try_reserve(&[], usize::MAX),
+            Err(AllocError::CapacityOverflow)
+        );
+        unsafe { assert_eq!(table.read_mut::<A>(9).0, 9) };
+    }
+
+    #[test]
+    fn table_drops_live_rows() {
+        let drops = Rc::new(Cell::new(0));
+        let mut table = Table::new(Archetype::new());
+        unsafe {
+            for _ in 0..3 {
+                let index = table.reserve_index();
+                table.write(index, D(drops.clone()));
+            }
+
+            table.drop_row(1);
+            table.swap_remove(1);
+            assert_eq!(drops.get(), 1);
+            assert_eq!(table.len(), 2);
+        }
+
+        drop(table);
+        assert_eq!(drops.get(), 3);
+    }
 }
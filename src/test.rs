@@ -1,8 +1,19 @@
 #[cfg(test)]
 mod tests {
-    use crate::{self as ecs, component, ArchetypeBuilder, With, Without};
+    use crate::component::{Component, ComponentId, Metadata};
+    use crate::{
+        self as ecs, component, Added, AnyOf, ArchetypeBuilder, Changed, Has, Or, With, Without,
+    };
 
-    use crate::{Entity, World};
+    use crate::borrow::BorrowError;
+    use crate::error::EcsError;
+    use crate::error::ScheduleError;
+    use crate::schedule::resource_changed;
+    use crate::{
+        Commands, Entity, LastRun, RemovedComponents, Res, ResMut, Schedule, System, World,
+    };
+
+    use std::{alloc::Layout, any::TypeId, cell::Cell, rc::Rc};
 
     #[component]
     struct A(u32);
@@ -16,40 +27,72 @@ mod tests {
     #[component]
     struct Z {}
 
+    #[component]
+    struct G<T>(T);
+
+    #[component]
+    struct D(Rc<Cell<u32>>);
+
+    impl Drop for D {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[component(storage = "sparse")]
+    struct Stunned(u32);
+
+    #[component]
+    #[repr(align(64))]
+    struct CacheLine([u64; 3]);
+
+    #[component]
+    #[repr(align(32))]
+    struct Simd([f32; 8]);
+
+    #[component(storage = "sparse")]
+    struct SparseD(Rc<Cell<u32>>);
+
+    impl Drop for SparseD {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
     #[test]
     fn get_component() {
         let world: World = World::new();
 
-        let entity_ref = unsafe { world.spawn((A(42u32), B(false), C(Some("a")))) };
-        assert_eq!(entity_ref.0, 1);
+        let entity_ref = world.spawn((A(42u32), B(false), C(Some("a"))));
+        assert_eq!(entity_ref.index(), 1);
 
-        let (_, index) = (*world.inner().entities.get(1).unwrap()).unwrap();
+        let (_, index) = world.location(entity_ref).unwrap();
         assert_eq!(index, 0);
 
-        assert_eq!(world.component::<A>(Entity(1)).unwrap().0, 42);
-        assert!(!world.component::<B>(Entity(1)).unwrap().0);
-        assert_eq!(world.component::<C>(Entity(1)).unwrap().0, Some("a"));
+        assert_eq!(world.component::<A>(entity_ref).unwrap().0, 42);
+        assert!(!world.component::<B>(entity_ref).unwrap().0);
+        assert_eq!(world.component::<C>(entity_ref).unwrap().0, Some("a"));
 
         // repeat in different order
-        assert_eq!(world.component::<C>(Entity(1)).unwrap().0, Some("a"));
-        assert!(!world.component::<B>(Entity(1)).unwrap().0,);
-        assert_eq!(world.component::<A>(Entity(1)).unwrap().0, 42);
+        assert_eq!(world.component::<C>(entity_ref).unwrap().0, Some("a"));
+        assert!(!world.component::<B>(entity_ref).unwrap().0,);
+        assert_eq!(world.component::<A>(entity_ref).unwrap().0, 42);
 
-        world.component_mut::<A>(Entity(1)).unwrap().0 = 123u32;
-        world.component_mut::<B>(Entity(1)).unwrap().0 = true;
+        world.component_mut::<A>(entity_ref).unwrap().0 = 123u32;
+        world.component_mut::<B>(entity_ref).unwrap().0 = true;
 
-        assert_eq!(world.component::<A>(Entity(1)).unwrap().0, 123u32);
-        assert!(world.component::<B>(Entity(1)).unwrap().0);
+        assert_eq!(world.component::<A>(entity_ref).unwrap().0, 123u32);
+        assert!(world.component::<B>(entity_ref).unwrap().0);
 
-        assert!(world.has_component::<A>(Entity(1)));
-        assert!(!world.has_component::<Z>(Entity(1)));
+        assert!(world.has_component::<A>(entity_ref));
+        assert!(!world.has_component::<Z>(entity_ref));
     }
 
     #[test]
     fn get_nonexisting_component() {
         let world: World = World::new();
 
-        let e = unsafe { world.spawn(A(42u32)) };
+        let e = world.spawn(A(42u32));
         assert!(world.component::<B>(e).is_none());
         assert!(world.component_mut::<B>(e).is_none());
     }
@@ -58,186 +101,1635 @@ mod tests {
     fn query() {
         let world: World = World::new();
 
-        unsafe {
-            world.spawn((A(1u32), C(Some("1"))));
-            world.spawn((A(2u32), C(Some("2")), B(true)));
-            world.spawn((A(10u32), C(Some("10"))));
+        world.spawn((A(1u32), C(Some("1"))));
+        world.spawn((A(2u32), C(Some("2")), B(true)));
+        world.spawn((A(10u32), C(Some("10"))));
 
-            let mut count = 0;
-            world.run(|a: &mut A, c: &C| {
-                assert_eq!(Some(a.0.to_string().as_str()), c.0);
-                a.0 = 123;
-                count += 1;
-            });
-            assert_eq!(count, 3);
+        let mut count = 0;
+        world.run(|a: &mut A, c: &C| {
+            assert_eq!(Some(a.0.to_string().as_str()), c.0);
+            a.0 = 123;
+            count += 1;
+        });
+        assert_eq!(count, 3);
 
-            world.run(|c1: &mut A| {
-                assert_eq!(c1.0, 123);
-            });
-        }
+        world.run(|c1: &mut A| {
+            assert_eq!(c1.0, 123);
+        });
     }
 
     #[test]
     fn query_with_optional() {
         let world: World = World::new();
 
-        unsafe {
-            world.spawn((B(true), A(1)));
-            world.spawn(B(true));
-            world.spawn((B(true), A(5)));
-            world.spawn(B(true));
+        world.spawn((B(true), A(1)));
+        world.spawn(B(true));
+        world.spawn((B(true), A(5)));
+        world.spawn(B(true));
 
-            world.run(|_: &B, a: Option<&mut A>| {
-                if let Some(a) = a {
-                    a.0 = 4;
-                }
-            });
+        world.run(|_: &B, a: Option<&mut A>| {
+            if let Some(a) = a {
+                a.0 = 4;
+            }
+        });
 
-            let mut sum = 0;
-            world.run(|_: &B, a: Option<&A>| {
-                if let Some(a) = a {
-                    sum += a.0;
-                }
-            });
-            assert_eq!(sum, 8);
-        }
+        let mut sum = 0;
+        world.run(|_: &B, a: Option<&A>| {
+            if let Some(a) = a {
+                sum += a.0;
+            }
+        });
+        assert_eq!(sum, 8);
     }
 
     #[test]
     fn query_with_entity() {
         let world: World = World::new();
 
-        unsafe {
-            world.spawn((B(true), A(1)));
-            world.spawn(B(true));
-            world.spawn((B(true), A(5)));
-            world.spawn(B(true));
+        world.spawn((B(true), A(1)));
+        world.spawn(B(true));
+        world.spawn((B(true), A(5)));
+        world.spawn(B(true));
 
-            let mut sum = 0;
-            world.run(|entity: &Entity, _: &B| {
-                sum += **entity - 1;
-            });
+        let mut sum = 0;
+        world.run(|entity: &Entity, _: &B| {
+            sum += **entity - 1;
+        });
 
-            assert_eq!(sum, 6);
-        }
+        assert_eq!(sum, 6);
     }
 
     #[test]
     fn despawn() {
         let world: World = World::new();
 
-        unsafe {
-            world.spawn(A(1));
-            world.spawn(A(2));
-            let e = world.spawn(A(3));
-            world.spawn(A(4));
+        world.spawn(A(1));
+        world.spawn(A(2));
+        let e = world.spawn(A(3));
+        world.spawn(A(4));
 
-            world.despawn(e);
+        world.despawn(e).unwrap();
 
-            let mut sum = 0;
-            world.run(|a: &A| {
-                sum += a.0;
-            });
+        let mut sum = 0;
+        world.run(|a: &A| {
+            sum += a.0;
+        });
 
-            assert_eq!(sum, 7);
-        }
+        assert_eq!(sum, 7);
     }
 
     #[test]
     fn reuse_entity() {
         let world: World = World::new();
 
-        unsafe {
-            world.spawn(A(1));
-            world.spawn(A(2));
-            world.spawn(A(3));
-            world.spawn(A(4));
-            world.spawn(A(5));
+        world.spawn(A(1));
+        world.spawn(A(2));
+        world.spawn(A(3));
+        world.spawn(A(4));
+        world.spawn(A(5));
 
-            world.despawn(Entity(3));
-            assert_eq!(world.spawn(A(3)), Entity(3));
-            assert_eq!(world.spawn(A(6)), Entity(6));
-        }
+        world.despawn(Entity::new(3, 0)).unwrap();
+        assert_eq!(world.spawn(A(3)), Entity::new(3, 1));
+        assert_eq!(world.spawn(A(6)), Entity::new(6, 0));
     }
 
     #[test]
     fn with_without() {
         let world: World = World::new();
 
-        unsafe {
-            world.spawn((A(1), B(false)));
-            world.spawn(A(2));
-            world.spawn((A(3), B(false)));
-            world.spawn(A(4));
+        world.spawn((A(1), B(false)));
+        world.spawn(A(2));
+        world.spawn((A(3), B(false)));
+        world.spawn(A(4));
 
-            let mut sum = 0;
-            world.run(|a: &A, _: Without<B>| {
-                sum += a.0;
-            });
-            assert_eq!(sum, 6);
+        let mut sum = 0;
+        world.run(|a: &A, _: Without<B>| {
+            sum += a.0;
+        });
+        assert_eq!(sum, 6);
 
-            let mut sum = 0;
-            world.run(|a: &A, _: With<B>| {
-                sum += a.0;
-            });
-            assert_eq!(sum, 4);
-        }
+        let mut sum = 0;
+        world.run(|a: &A, _: With<B>| {
+            sum += a.0;
+        });
+        assert_eq!(sum, 4);
     }
 
     #[test]
     fn add_remove_component() {
         let world: World = World::new();
 
-        unsafe {
-            let e1 = world.spawn(A(1));
-            let e2 = world.spawn(A(2));
-            let e3 = world.spawn(A(3));
-            let e4 = world.spawn((A(4), C(Some("bar"))));
+        let e1 = world.spawn(A(1));
+        let e2 = world.spawn(A(2));
+        let e3 = world.spawn(A(3));
+        let e4 = world.spawn((A(4), C(Some("bar"))));
 
-            world.add_component(e2, C(Some("foo")));
-            world.add_component(e2, C(Some("foo")));
-            assert!(world.has_component::<A>(e2));
-            assert!(world.has_component::<C>(e2));
-            assert_eq!(world.component::<A>(e2).unwrap().0, 2);
-            assert!(world.has_component::<Entity>(e1));
-            assert!(world.has_component::<Entity>(e2));
-            assert!(world.has_component::<Entity>(e3));
-            assert!(world.has_component::<A>(e1));
-            assert!(world.has_component::<A>(e2));
+        world.add_component(e2, C(Some("foo"))).unwrap();
+        assert_eq!(
+            world.add_component(e2, C(Some("foo"))),
+            Err(EcsError::AlreadyHasComponent(e2, C::metadata_static().id()))
+        );
+        assert!(world.has_component::<A>(e2));
+        assert!(world.has_component::<C>(e2));
+        assert_eq!(world.component::<A>(e2).unwrap().0, 2);
+        assert!(world.has_component::<Entity>(e1));
+        assert!(world.has_component::<Entity>(e2));
+        assert!(world.has_component::<Entity>(e3));
+        assert!(world.has_component::<A>(e1));
+        assert!(world.has_component::<A>(e2));
 
-            world.remove_component::<A>(e2);
-            world.remove_component::<A>(e2);
-            assert!(!world.has_component::<A>(e2));
-            assert!(world.has_component::<C>(e2));
-            assert!(world.has_component::<Entity>(e2));
-            assert!(world.has_component::<Entity>(e4));
-            assert!(world.has_component::<A>(e4));
-            assert!(world.has_component::<C>(e4));
+        world.remove_component::<A>(e2).unwrap();
+        assert_eq!(
+            world.remove_component::<A>(e2),
+            Err(EcsError::MissingComponent(e2, A::metadata_static().id()))
+        );
+        assert!(!world.has_component::<A>(e2));
+        assert!(world.has_component::<C>(e2));
+        assert!(world.has_component::<Entity>(e2));
+        assert!(world.has_component::<Entity>(e4));
+        assert!(world.has_component::<A>(e4));
+        assert!(world.has_component::<C>(e4));
 
-            world.remove_component::<C>(e2);
-        }
+        world.remove_component::<C>(e2).unwrap();
     }
 
     #[test]
     fn for_each_with_archetype() {
         let world: World = World::new();
 
-        unsafe {
-            world.spawn(A(1));
-            world.spawn(A(2));
-            let ent = world.spawn((A(1), B(false)));
-            world.spawn((A(1), B(false)));
-            world.spawn(A(3));
-            world.spawn(A(4));
+        world.spawn(A(1));
+        world.spawn(A(2));
+        let ent = world.spawn((A(1), B(false)));
+        world.spawn((A(1), B(false)));
+        world.spawn(A(3));
+        world.spawn(A(4));
+
+        world.despawn(ent).unwrap();
 
-            world.despawn(ent);
+        let archetype = ArchetypeBuilder::new().set::<A>().build();
+        let mut acc = 0;
+        world.for_each_with_archetype(archetype.clone(), |ent| {
+            acc += world.component::<A>(ent).unwrap().0;
+        });
+        assert_eq!(10, acc);
 
-            let archetype = ArchetypeBuilder::new().set::<A>().build();
-            let mut acc = 0;
+        // a panic ends the iteration, after which changes are applied right away again
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
             world.for_each_with_archetype(archetype, |ent| {
-                acc += world.component::<A>(ent).unwrap().0;
+                world.add_component(ent, B(true)).unwrap();
+                panic!();
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(world.query::<&B>().count(), 2);
+        let e = world.spawn(A(5));
+        world.add_component(e, B(true)).unwrap();
+        assert!(world.has_component::<B>(e));
+    }
+
+    #[test]
+    fn stale_entity() {
+        let world: World = World::new();
+
+        let e1 = world.spawn(A(1));
+        world.despawn(e1).unwrap();
+        let e2 = world.spawn(A(2));
+
+        assert_eq!(e1.index(), e2.index());
+        assert_ne!(e1, e2);
+        assert!(!world.is_alive(e1));
+        assert!(world.is_alive(e2));
+
+        assert!(world.component::<A>(e1).is_none());
+        assert!(world.component_mut::<A>(e1).is_none());
+        assert!(!world.has_component::<A>(e1));
+
+        assert_eq!(
+            world.add_component(e1, B(true)),
+            Err(EcsError::StaleEntity(e1))
+        );
+        assert!(!world.has_component::<B>(e2));
+
+        assert_eq!(world.despawn(e1), Err(EcsError::StaleEntity(e1)));
+        assert!(world.is_alive(e2));
+        assert_eq!(world.component::<A>(e2).unwrap().0, 2);
+    }
+
+    #[test]
+    fn drop_components() {
+        let world: World = World::new();
+        let drops = Rc::new(Cell::new(0));
+
+        let e1 = world.spawn((A(1), D(drops.clone())));
+        let e2 = world.spawn((A(2), D(drops.clone())));
+        let e3 = world.spawn(A(3));
+
+        world.despawn(e1).unwrap();
+        assert_eq!(drops.get(), 1);
+
+        world.remove_component::<D>(e2).unwrap();
+        assert_eq!(drops.get(), 2);
+
+        world.add_component(e3, D(drops.clone())).unwrap();
+        assert_eq!(drops.get(), 2);
+        assert!(world.add_component(e3, D(drops.clone())).is_err());
+        assert_eq!(drops.get(), 3);
+
+        world.run(|_: &A| {
+            world.add_component(e2, D(drops.clone())).unwrap();
+        });
+        assert_eq!(drops.get(), 4);
+        assert!(world.has_component::<D>(e2));
+
+        world.insert(e3, A(4));
+        assert_eq!(drops.get(), 5);
+        assert!(!world.has_component::<D>(e3));
+
+        world.destroy_component::<D>(e2).unwrap();
+        assert_eq!(drops.get(), 6);
+    }
+
+    #[test]
+    fn drop_world() {
+        let drops = Rc::new(Cell::new(0));
+
+        let world: World = World::new();
+        world.spawn((A(1), D(drops.clone())));
+        world.spawn(D(drops.clone()));
+
+        // pointing a world at its own state keeps it
+        let mut same: World = World::new();
+        let e = same.spawn(A(1));
+        unsafe { same.set_inner_from_raw(same.as_raw()) };
+        assert!(same.is_owned());
+        assert_eq!(same.component::<A>(e).unwrap().0, 1);
+        drop(same);
+
+        let view = unsafe { World::from_raw(world.as_raw()) };
+        assert!(!view.is_owned());
+        drop(view);
+        assert_eq!(drops.get(), 0);
+        assert_eq!(world.num_entities_max(), 3);
+
+        let world = unsafe { World::from_raw_owned(world.into_raw()) };
+        assert!(world.is_owned());
+        drop(world);
+        assert_eq!(drops.get(), 2);
+    }
+
+    struct Big(u64);
+
+    impl Component for Big {
+        fn metadata(&self) -> Metadata {
+            Self::metadata_static()
+        }
+
+        fn metadata_static() -> Metadata {
+            Metadata::of::<Self>(ComponentId(1000), "Big")
+        }
+    }
+
+    #[test]
+    fn large_component_ids() {
+        let world: World = World::new();
+
+        let e1 = world.spawn((A(1), Big(10)));
+        let e2 = world.spawn(A(2));
+        world.add_component(e2, Big(20)).unwrap();
+        world.add_component(e1, B(true)).unwrap();
+
+        let mut sum = 0;
+        world.run(|a: &A, big: &Big| {
+            sum += a.0 as u64 + big.0;
+        });
+        assert_eq!(sum, 33);
+
+        world.remove_component::<Big>(e1).unwrap();
+        assert!(!world.has_component::<Big>(e1));
+        assert!(world.has_component::<B>(e1));
+        assert_eq!(world.component::<Big>(e2).unwrap().0, 20);
+
+        let archetype = ArchetypeBuilder::new().set::<A>().set::<Big>().build();
+        let mut count = 0;
+        world.for_each_with_archetype(archetype, |_| count += 1);
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn component_registry() {
+        let a = A::metadata_static();
+        let b = B::metadata_static();
+        assert_ne!(a.id(), b.id());
+        assert_ne!(a.id(), Entity::metadata_static().id());
+        assert_eq!(A(0).metadata().id(), a.id());
+
+        assert_eq!(
+            ecs::component::id_of(std::any::TypeId::of::<A>()),
+            Some(a.id())
+        );
+        assert_eq!(ecs::component::metadata_of(b.id()).unwrap().name(), "B");
+        assert_eq!(
+            ecs::component::metadata_of(ComponentId(0)).unwrap().name(),
+            "Entity"
+        );
+
+        let g1 = G::<u8>::metadata_static();
+        let g2 = G::<u64>::metadata_static();
+        assert_ne!(g1.id(), g2.id());
+        assert_eq!(g1.id(), G::<u8>::metadata_static().id());
+        assert_eq!(g2.size(), 8);
+    }
+
+    #[test]
+    fn nested_run_borrows() {
+        let world: World = World::new();
+
+        world.spawn((A(1), B(true)));
+
+        let a_id = A::metadata_static().id();
+
+        world.run(|_: &A, _: &B| {
+            world.run(|_: &A| {});
+            assert_eq!(
+                world.try_run(|_: &mut A| {}),
+                Err(BorrowError::AlreadyBorrowed(a_id))
+            );
+        });
+
+        world.run(|_: &mut A| {
+            assert_eq!(
+                world.try_run(|_: &A| {}),
+                Err(BorrowError::AlreadyMutablyBorrowed(a_id))
+            );
+            world.run(|_: &B| {});
+        });
+
+        assert_eq!(
+            world.try_run(|_: &mut A, _: Option<&A>| {}),
+            Err(BorrowError::AlreadyBorrowed(a_id))
+        );
+
+        // everything is released again
+        world.run(|_: &mut A, _: &mut B| {});
+    }
+
+    #[test]
+    #[should_panic(expected = "component A is already mutably borrowed")]
+    fn component_while_borrowed_mutably() {
+        let world: World = World::new();
+
+        let e = world.spawn(A(1));
+        world.run(|_: &mut A| {
+            world.component::<A>(e);
+        });
+    }
+
+    #[test]
+    fn component_guards() {
+        let world: World = World::new();
+
+        let e = world.spawn((A(1), B(false)));
+
+        let a = world.component::<A>(e).unwrap();
+        let b = world.component_mut::<B>(e).unwrap();
+        assert_eq!(a.0, 1);
+        assert!(world.try_run(|_: &B| {}).is_err());
+        assert!(world.try_run(|_: &A| {}).is_ok());
+
+        // structural changes wait for the guards
+        world.despawn(e).unwrap();
+        assert!(world.is_alive(e));
+        drop(a);
+        assert!(world.is_alive(e));
+        drop(b);
+        assert!(!world.is_alive(e));
+    }
+
+    #[test]
+    fn despawn_in_system() {
+        let world: World = World::new();
+
+        world.spawn(A(1));
+        world.spawn(A(2));
+        world.spawn(A(3));
+
+        let mut sum = 0;
+        world.run(|e: &Entity, a: &A| {
+            if a.0 % 2 == 1 {
+                world.despawn(*e).unwrap();
+            }
+            sum += a.0;
+        });
+        assert_eq!(sum, 6);
+
+        let mut sum = 0;
+        world.run(|a: &A| sum += a.0);
+        assert_eq!(sum, 2);
+    }
+
+    #[test]
+    fn commands() {
+        let world: World = World::new();
+        let drops = Rc::new(Cell::new(0));
+
+        let e1 = world.spawn(A(1));
+        let e2 = world.spawn((A(2), B(true), C(None)));
+
+        let mut spawned = Vec::new();
+        world.run(|e: &Entity, a: &A, mut commands: Commands| {
+            let new = commands.spawn((A(a.0 * 10), D(drops.clone())));
+            assert!(!world.is_alive(new));
+            spawned.push(new);
+            commands.despawn(*e);
+        });
+
+        assert_eq!(spawned.len(), 2);
+        assert!(!world.is_alive(e1));
+        assert!(!world.is_alive(e2));
+        for e in &spawned {
+            assert!(world.is_alive(*e));
+            assert!(world.has_component::<D>(*e));
+        }
+
+        let mut sum = 0;
+        world.run(|a: &A| sum += a.0);
+        assert_eq!(sum, 30);
+
+        // reserved ids do not collide with entities spawned right away
+        let reserved = world.commands().spawn(A(100));
+        let e3 = world.spawn(A(3));
+        assert_ne!(reserved, e3);
+        assert!(!world.is_alive(reserved));
+        world.flush();
+        assert!(world.is_alive(reserved));
+        assert_eq!(world.component::<A>(reserved).unwrap().0, 100);
+        assert_eq!(world.component::<A>(e3).unwrap().0, 3);
+
+        let mut commands = world.commands();
+        commands.add_bundle(e3, (B(false), C(Some("c"))));
+        commands.remove_bundle::<(A, B)>(reserved);
+        commands.insert(spawned[0], B(true));
+        world.flush();
+
+        assert!(world.has_component::<B>(e3));
+        assert!(world.has_component::<C>(e3));
+        assert!(world.is_alive(reserved));
+        assert!(!world.has_component::<A>(reserved));
+        assert!(world.has_component::<B>(spawned[0]));
+        assert!(!world.has_component::<A>(spawned[0]));
+        assert_eq!(drops.get(), 1);
+
+        // pending commands are dropped with the world
+        world.commands().spawn(D(drops.clone()));
+        drop(world);
+        assert_eq!(drops.get(), 3);
+    }
+
+    #[test]
+    fn dense_tables() {
+        let world: World = World::new();
+
+        let entities: Vec<Entity> = (0..10).map(|i| world.spawn(A(i))).collect();
+        for e in entities.iter().step_by(2) {
+            world.despawn(*e).unwrap();
+        }
+
+        let table_id = world.location(entities[1]).unwrap().0;
+        assert_eq!(world.table(table_id).len(), 5);
+
+        for (i, e) in entities.iter().enumerate() {
+            if i % 2 == 1 {
+                assert_eq!(world.component::<A>(*e).unwrap().0, i as u32);
+            } else {
+                assert!(world.component::<A>(*e).is_none());
+            }
+        }
+
+        // moving entities out of a table keeps both tables dense
+        world.add_component(entities[1], B(true)).unwrap();
+        world.add_component(entities[3], B(true)).unwrap();
+        assert_eq!(world.table(table_id).len(), 3);
+        assert_eq!(world.component::<A>(entities[9]).unwrap().0, 9);
+        world.remove_component::<B>(entities[1]).unwrap();
+        assert_eq!(world.table(table_id).len(), 4);
+        assert_eq!(world.component::<A>(entities[3]).unwrap().0, 3);
+
+        let mut count = 0;
+        world.for_each_with_archetype_subset(ArchetypeBuilder::new().set::<A>().build(), |e| {
+            world.despawn(e).unwrap();
+            count += 1;
+        });
+        assert_eq!(count, 5);
+        assert_eq!(world.table(table_id).len(), 0);
+    }
+
+    #[test]
+    fn transition_edges() {
+        let world: World = World::new();
+        let e = world.spawn(A(1));
+        let src = world.location(e).unwrap().0;
+
+        world.add_component(e, B(true)).unwrap();
+        let dst = world.location(e).unwrap().0;
+        let num_tables = world.inner().tables.len();
+
+        // both directions are cached after the first move
+        let b = B::metadata_static().id();
+        assert_eq!(world.table(src).add_edge(b).unwrap().target, dst);
+        assert_eq!(world.table(dst).remove_edge(b).unwrap().target, src);
+
+        for i in 0..10 {
+            world.remove_component::<B>(e).unwrap();
+            assert_eq!(world.location(e).unwrap().0, src);
+            world.add_component(e, B(i % 2 == 0)).unwrap();
+            assert_eq!(world.location(e).unwrap().0, dst);
+        }
+        assert_eq!(world.inner().tables.len(), num_tables);
+        assert_eq!(world.component::<A>(e).unwrap().0, 1);
+        assert!(!world.component::<B>(e).unwrap().0);
+
+        // adding a component the entity already has does not create an edge
+        assert!(world.add_component(e, A(2)).is_err());
+        assert_eq!(world.component::<A>(e).unwrap().0, 1);
+        assert!(world
+            .table(dst)
+            .add_edge(A::metadata_static().id())
+            .is_none());
+    }
+
+    #[test]
+    fn cached_query_matching() {
+        let world: World = World::new();
+        world.spawn(A(1));
+        world.spawn(B(true));
+
+        let mut sum = 0;
+        world.run(|a: &A| sum += a.0);
+        assert_eq!(sum, 1);
+
+        // tables created after the first run are picked up by the next one
+        world.spawn((A(2), B(false)));
+        world.spawn(C(None));
+        sum = 0;
+        world.run(|a: &A| sum += a.0);
+        assert_eq!(sum, 3);
+
+        let mut count = 0;
+        world.run(|_: &A, _: Without<B>| count += 1);
+        assert_eq!(count, 1);
+
+        assert_eq!(world.query::<(&A, With<B>)>().count(), 1);
+
+        let cache = world.inner().query_cache.lock().unwrap();
+        assert!(cache
+            .values()
+            .all(|c| c.generation == world.inner().tables.len()));
+        // every query and parameter list has its own entry
+        let tables = |key: TypeId| cache[&key].tables.len();
+        assert_eq!(cache.len(), 3);
+        assert_eq!(tables(TypeId::of::<(&A,)>()), 2);
+        assert_eq!(tables(TypeId::of::<(&A, Without<B>)>()), 1);
+        assert_eq!(tables(TypeId::of::<(&A, With<B>)>()), 1);
+    }
+
+    #[test]
+    fn sparse_components() {
+        let world: World = World::new();
+        let e1 = world.spawn(A(1));
+        let e2 = world.spawn(A(2));
+        let e3 = world.spawn((A(3), Stunned(30)));
+        let table_id = world.location(e1).unwrap().0;
+        assert_eq!(world.location(e3).unwrap().0, table_id);
+
+        // toggling a sparse component leaves the entity where it is
+        world.add_component(e1, Stunned(10)).unwrap();
+        assert_eq!(world.location(e1).unwrap(), (table_id, 0));
+        assert_eq!(world.inner().tables.len(), 1);
+        assert!(world.has_component::<Stunned>(e1));
+        assert!(!world.has_component::<Stunned>(e2));
+        assert_eq!(world.component::<Stunned>(e1).unwrap().0, 10);
+        world.component_mut::<Stunned>(e1).unwrap().0 = 11;
+
+        let mut stunned = vec![];
+        world.run(|a: &A, s: &Stunned| stunned.push((a.0, s.0)));
+        assert_eq!(stunned, vec![(1, 11), (3, 30)]);
+
+        let mut count = 0;
+        world.run(|_: &A, _: With<Stunned>| count += 1);
+        assert_eq!(count, 2);
+
+        let mut free = vec![];
+        world.run(|a: &A, _: Without<Stunned>| free.push(a.0));
+        assert_eq!(free, vec![2]);
+
+        let mut all = vec![];
+        world.run(|a: &A, s: Option<&mut Stunned>| {
+            if let Some(s) = s {
+                s.0 += 1;
+            }
+            all.push(a.0)
+        });
+        assert_eq!(all, vec![1, 2, 3]);
+        assert_eq!(world.component::<Stunned>(e3).unwrap().0, 31);
+
+        world.remove_component::<Stunned>(e1).unwrap();
+        assert!(!world.has_component::<Stunned>(e1));
+        assert_eq!(world.component::<Stunned>(e3).unwrap().0, 31);
+        assert_eq!(world.location(e1).unwrap(), (table_id, 0));
+
+        // sparse components go away with their entity and are dropped
+        let drops = Rc::new(Cell::new(0));
+        world.add_component(e2, SparseD(drops.clone())).unwrap();
+        world.add_component(e3, SparseD(drops.clone())).unwrap();
+        world.despawn(e2).unwrap();
+        assert_eq!(drops.get(), 1);
+        let e4 = world.spawn(A(4));
+        assert_eq!(e4.index(), e2.index());
+        assert!(!world.has_component::<SparseD>(e4));
+        world.remove_component::<SparseD>(e3).unwrap();
+        assert_eq!(drops.get(), 2);
+
+        world.add_component(e4, SparseD(drops.clone())).unwrap();
+        drop(world);
+        assert_eq!(drops.get(), 3);
+    }
+
+    #[test]
+    fn zero_sized_tags() {
+        let world: World = World::new();
+        let entities: Vec<Entity> = (0..100).map(|i| world.spawn((A(i), Z {}))).collect();
+        for e in entities.iter().step_by(2) {
+            world.remove_component::<Z>(*e).unwrap();
+        }
+        world.add_component(entities[0], Z {}).unwrap();
+
+        let mut tagged = 0;
+        world.run(|_: &A, z: &Z| {
+            assert!((z as *const Z).is_aligned());
+            tagged += 1;
+        });
+        assert_eq!(tagged, 51);
+
+        let (table_id, _) = world.location(entities[1]).unwrap();
+        let z = Z::metadata_static().id();
+        assert!(unsafe { world.table(table_id).get_column_by_id(z) }
+            .unwrap()
+            .is_zero_sized());
+        let edge = world.table(table_id).remove_edge(z).unwrap();
+        assert!(!edge.shared.contains(&z));
+        assert_eq!(edge.shared.len(), 2);
+
+        assert!(world.has_component::<Z>(entities[0]));
+        assert!(!world.has_component::<Z>(entities[2]));
+        assert_eq!(world.component::<A>(entities[3]).unwrap().0, 3);
+
+        // The ticks of zero-sized items move with their row
+        let world: World = World::new();
+        let e1 = world.spawn((A(1), Z {}));
+        let e2 = world.spawn((A(2), Z {}));
+        world.add_component(e1, B(true)).unwrap();
+        world.add_component(e2, B(true)).unwrap();
+        let edge = world.add_edge(world.location(e1).unwrap().0, C::metadata_static());
+        assert!(edge.unwrap().zero_sized.contains(&z));
+        world.run(|_: &mut Z| {});
+        let mut changed = 0;
+        world.run(|_: &Z, _: Changed<Z>, _: Added<Z>| changed += 1);
+        assert_eq!(changed, 2);
+        world.remove_component::<B>(e1).unwrap();
+        world.run(|_: &mut Z, _: &A| {});
+    }
+
+    #[test]
+    fn fallible_allocation() {
+        let world: World = World::new();
+        assert!(world.try_reserve::<(A, B)>(usize::MAX / 2).is_err());
+
+        world.try_reserve::<(A, B)>(16).unwrap();
+        let entities: Vec<Entity> = (0..16)
+            .map(|i| world.try_spawn((A(i), B(false))).unwrap())
+            .collect();
+        assert_eq!(world.component::<A>(entities[15]).unwrap().0, 15);
+
+        world.try_add_component(entities[0], C(None)).unwrap();
+        assert!(world.has_component::<C>(entities[0]));
+        world.try_add_component(entities[0], Stunned(1)).unwrap();
+        assert!(world.has_component::<Stunned>(entities[0]));
+
+        let e = world.try_insert(entities[1], A(100)).unwrap();
+        assert_eq!(world.component::<A>(e).unwrap().0, 100);
+        assert!(!world.has_component::<B>(e));
+
+        // Growing the columns would move the items the system borrows
+        let world: World = World::new();
+        world.spawn(A(0xAAAA));
+        world.run(|a: &A| {
+            assert_eq!(world.try_reserve::<A>(1 << 20), Err(EcsError::Deferred));
+            assert_eq!(a.0, 0xAAAA);
+        });
+        {
+            let _a = world.query::<&A>();
+            assert_eq!(world.try_reserve::<B>(16), Err(EcsError::Deferred));
+        }
+        world.try_reserve::<A>(16).unwrap();
+    }
+
+    #[test]
+    fn command_errors() {
+        use std::sync::{Arc, Mutex};
+
+        let world: World = World::new();
+        let e1 = world.spawn(A(1));
+        let e2 = world.spawn(A(2));
+        world.despawn(e2).unwrap();
+
+        assert_eq!(
+            world.despawn(Entity::new(100, 0)),
+            Err(EcsError::NoSuchEntity(Entity::new(100, 0)))
+        );
+        assert_eq!(world.despawn(e2), Err(EcsError::StaleEntity(e2)));
+        assert_eq!(
+            world.remove_component::<B>(e1),
+            Err(EcsError::MissingComponent(e1, B::metadata_static().id()))
+        );
+        assert_eq!(
+            world.add_component(e2, B(true)),
+            Err(EcsError::StaleEntity(e2))
+        );
+        assert_eq!(
+            EcsError::MissingComponent(e1, B::metadata_static().id()).to_string(),
+            "entity 1 does not have component B"
+        );
+
+        let errors = Arc::new(Mutex::new(vec![]));
+        let errors_ = errors.clone();
+        world.on_command_error(move |err| errors_.lock().unwrap().push(err));
+
+        world.run(|e: &Entity, mut commands: Commands| {
+            commands.add_component(*e, A(10));
+            commands.remove_component::<B>(*e);
+            commands.despawn(e2);
+            commands.add_component(*e, B(true));
+        });
+
+        assert_eq!(
+            *errors.lock().unwrap(),
+            vec![
+                EcsError::AlreadyHasComponent(e1, A::metadata_static().id()),
+                EcsError::MissingComponent(e1, B::metadata_static().id()),
+                EcsError::StaleEntity(e2),
+            ]
+        );
+        assert!(world.has_component::<B>(e1));
+    }
+
+    #[test]
+    fn over_aligned_components() {
+        let world: World = World::new();
+        let entities: Vec<Entity> = (0..20)
+            .map(|i| world.spawn((B(true), CacheLine([i; 3]))))
+            .collect();
+
+        // every move creates a table from the columns of the previous one
+        for (i, e) in entities.iter().enumerate() {
+            world.add_component(*e, A(i as u32)).unwrap();
+            world.add_component(*e, Simd([i as f32; 8])).unwrap();
+            world.remove_component::<B>(*e).unwrap();
+            world.add_component(*e, C(None)).unwrap();
+        }
+
+        let (table_id, _) = world.location(entities[0]).unwrap();
+        for (id, layout) in [
+            (
+                CacheLine::metadata_static().id(),
+                Layout::new::<CacheLine>(),
+            ),
+            (Simd::metadata_static().id(), Layout::new::<Simd>()),
+            (A::metadata_static().id(), Layout::new::<A>()),
+        ] {
+            let col = unsafe { world.table(table_id).get_column_by_id(id).unwrap() };
+            assert_eq!(col.item_layout(), layout);
+        }
+
+        let mut count = 0;
+        world.run(|a: &A, line: &CacheLine, simd: &mut Simd| {
+            assert!((line as *const CacheLine).is_aligned());
+            assert!((simd as *const Simd).is_aligned());
+            assert_eq!(line.0, [a.0 as u64; 3]);
+            assert_eq!(simd.0, [a.0 as f32; 8]);
+            simd.0[0] += 1.0;
+            count += 1;
+        });
+        assert_eq!(count, 20);
+
+        let simd = world.component::<Simd>(entities[19]).unwrap();
+        assert!((&*simd as *const Simd).is_aligned());
+        assert_eq!(simd.0[0], 20.0);
+    }
+
+    #[test]
+    fn query_api() {
+        let world: World = World::new();
+        let e1 = world.spawn((A(1), B(false)));
+        let e2 = world.spawn((A(2), B(true), C(None)));
+        let e3 = world.spawn(A(3));
+        let e4 = world.spawn(B(false));
+        world.add_component(e3, Stunned(5)).unwrap();
+
+        {
+            let mut query = world.query::<(&A, &mut B, Option<&C>, Without<Z>)>();
+            assert_eq!(query.count(), 2);
+            assert!(!query.is_empty());
+
+            for (a, b, c, _) in query.iter_mut() {
+                b.0 = a.0 == 1 && c.is_none();
+            }
+            let flags: Vec<_> = query.iter().map(|(a, b, _, _)| (a.0, b.0)).collect();
+            assert_eq!(flags, vec![(1, true), (2, false)]);
+
+            // reusable, and read-only items can be held together
+            let first = query.get(e1).unwrap();
+            let second = query.get(e2).unwrap();
+            assert_eq!((first.0 .0, second.0 .0), (1, 2));
+            assert!(second.2.is_some());
+            assert!(query.get(e3).is_none());
+            assert!(query.get(e4).is_none());
+
+            query.get_mut(e2).unwrap().1 .0 = true;
+            assert!(world.try_query::<&B>().is_err());
+
+            // structural changes wait until the query is dropped
+            world.despawn(e1).unwrap();
+            assert_eq!(query.count(), 2);
+        }
+        assert!(world.component::<B>(e2).unwrap().0);
+        assert!(world.location(e1).is_none());
+
+        let query = world.query::<(&Entity, &Stunned)>();
+        let (e, stunned) = query.single().unwrap();
+        assert_eq!((*e, stunned.0), (e3, 5));
+        drop(query);
+
+        let mut query = world.query::<(&mut A, Without<Stunned>)>();
+        assert!(query.single().is_some());
+        query.single_mut().unwrap().0 .0 += 10;
+        drop(query);
+        assert_eq!(world.component::<A>(e2).unwrap().0, 12);
+
+        assert!(world.query::<&Z>().is_empty());
+        assert!(world.query::<&A>().single().is_none());
+        assert!(matches!(
+            world.try_query::<(&mut A, &A)>(),
+            Err(BorrowError::AlreadyBorrowed(_))
+        ));
+
+        let _reader = world.query::<&A>();
+        assert!(world.try_query::<&A>().is_ok());
+        assert!(matches!(
+            world.try_query::<&mut A>(),
+            Err(BorrowError::AlreadyMutablyBorrowed(_) | BorrowError::AlreadyBorrowed(_))
+        ));
+    }
+
+    #[test]
+    fn change_detection() {
+        // Not `#[track_caller]`, every system keeps its own last run
+        fn run<'a, P>(world: &'a World, last_run: &mut LastRun, f: impl System<'a, P>) {
+            world.run_with(last_run, f);
+        }
+
+        let mut added_run = LastRun::new();
+        let mut added = |world: &World| -> Vec<u32> {
+            let mut seen = vec![];
+            run(world, &mut added_run, |a: &A, _: Added<A>| seen.push(a.0));
+            seen.sort();
+            seen
+        };
+
+        let mut changed_run = LastRun::new();
+        let mut changed = |world: &World| -> Vec<u32> {
+            let mut seen = vec![];
+            run(
+                world,
+                &mut changed_run,
+                |a: &A, _: Changed<A>, _: Without<C>| seen.push(a.0),
+            );
+            seen.sort();
+            seen
+        };
+
+        let world: World = World::new();
+        let e1 = world.spawn((A(1), B(false)));
+        let e2 = world.spawn(A(2));
+        let e3 = world.spawn(B(true));
+
+        assert_eq!(added(&world), [1, 2]);
+        assert_eq!(added(&world), []);
+        assert_eq!(changed(&world), [1, 2]);
+        assert_eq!(changed(&world), []);
+
+        world.run(|a: &mut A, _: With<B>| a.0 += 10);
+        world.run(|_: &A| {});
+        assert_eq!(changed(&world), [11]);
+        assert_eq!(added(&world), []);
+
+        world.component_mut::<A>(e2).unwrap().0 = 20;
+        let _ = world.component::<A>(e1).unwrap().0;
+        assert_eq!(changed(&world), [20]);
+
+        world.add_component(e3, A(3)).unwrap();
+        assert_eq!(added(&world), [3]);
+        assert_eq!(changed(&world), [3]);
+
+        // moving to another table keeps the ticks
+        world.add_component(e2, C(None)).unwrap();
+        world.remove_component::<C>(e2).unwrap();
+        assert_eq!(added(&world), []);
+        assert_eq!(changed(&world), []);
+
+        // a system that never ran sees everything
+        let mut count = 0;
+        world.run(|_: Changed<A>| count += 1);
+        assert_eq!(count, 3);
+
+        // a filter does not conflict with writing the same component
+        let mut query_run = LastRun::new();
+        let mut query = world.query_with::<(&mut A, Changed<A>, With<B>)>(&mut query_run);
+        assert_eq!(query.count(), 2);
+        for (a, _, _) in query.iter_mut() {
+            a.0 += 100;
+        }
+        drop(query);
+        assert_eq!(changed(&world), [103, 111]);
+        assert!(world.try_query::<(&mut A, Added<A>)>().is_ok());
+        assert_eq!(
+            world
+                .query_with::<(&A, Changed<A>, With<B>)>(&mut query_run)
+                .count(),
+            0
+        );
+
+        // a failed borrow does not count as a change
+        {
+            let _a = world.component::<A>(e2).unwrap();
+            assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                world.component_mut::<A>(e2);
+            }))
+            .is_err());
+        }
+        assert_eq!(changed(&world), []);
+
+        world.add_component(e1, Stunned(1)).unwrap();
+        let mut stunned = vec![];
+        world.run(|e: &Entity, _: Added<Stunned>| stunned.push(*e));
+        assert_eq!(stunned, [e1]);
+    }
+
+    #[test]
+    fn removed_components() {
+        fn entities<T>(removed: Vec<(Entity, Option<T>)>) -> Vec<Entity> {
+            removed.into_iter().map(|(entity, _)| entity).collect()
+        }
+
+        let drops = Rc::new(Cell::new(0));
+        let world: World = World::new();
+        world.track_removed::<A>();
+        world.track_removed::<Stunned>();
+        world.keep_removed_values::<D>();
+
+        let e1 = world.spawn((A(1), D(drops.clone())));
+        let e2 = world.spawn((A(2), B(true)));
+        let e3 = world.spawn(B(false));
+        world.add_component(e2, Stunned(2)).unwrap();
+        world.add_component(e3, Stunned(3)).unwrap();
+
+        world.remove_component::<A>(e1).unwrap();
+        world.despawn(e2).unwrap();
+        assert_eq!(entities(world.removed::<A>()), [e1, e2]);
+        assert!(world.removed::<A>().is_empty());
+        assert_eq!(entities(world.removed::<Stunned>()), [e2]);
+        // not tracked until now
+        assert!(world.removed::<B>().is_empty());
+
+        world.remove_component::<D>(e1).unwrap();
+        assert_eq!(drops.get(), 0);
+        let removed = world.removed::<D>();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].0, e1);
+        assert!(removed[0].1.is_some());
+        drop(removed);
+        assert_eq!(drops.get(), 1);
+
+        // replacing an entity removes what it had
+        world.insert(e3, A(3));
+        assert_eq!(entities(world.removed::<B>()), [e3]);
+        assert_eq!(entities(world.removed::<Stunned>()), [e3]);
+
+        world.run(|e: &Entity, _: &A, mut commands: Commands| commands.despawn(*e));
+        let mut seen = vec![];
+        world.run(|mut removed: RemovedComponents<A>| seen.push(entities(removed.drain())));
+        assert_eq!(seen, [vec![e3]]);
+
+        // the system runs once per run, even without entities
+        let empty: World = World::new();
+        empty.track_removed::<A>();
+        let e = empty.spawn(A(0));
+        empty.despawn(e).unwrap();
+        let mut seen = vec![];
+        empty.run(|mut removed: RemovedComponents<A>| seen.push(entities(removed.drain())));
+        assert_eq!(seen, [vec![e]]);
+
+        // values are only kept for what is removed from then on
+        let late_drops = Rc::new(Cell::new(0));
+        let late: World = World::new();
+        late.track_removed::<D>();
+        let before = late.spawn(D(late_drops.clone()));
+        let after = late.spawn(D(late_drops.clone()));
+        late.remove_component::<D>(before).unwrap();
+        assert_eq!(late_drops.get(), 1);
+        late.keep_removed_values::<D>();
+        late.remove_component::<D>(after).unwrap();
+        assert_eq!(late_drops.get(), 1);
+        let removed = late.removed::<D>();
+        assert_eq!(removed.len(), 2);
+        assert_eq!((removed[0].0, removed[0].1.is_some()), (before, false));
+        assert_eq!((removed[1].0, removed[1].1.is_some()), (after, true));
+        drop(removed);
+        assert_eq!(late_drops.get(), 2);
+        let next = late.spawn(D(late_drops.clone()));
+        late.remove_component::<D>(next).unwrap();
+        assert!(late.removed::<D>()[0].1.is_some());
+        assert_eq!(late_drops.get(), 3);
+
+        // values still in the log are dropped with the world
+        let e4 = world.spawn(D(drops.clone()));
+        world.despawn(e4).unwrap();
+        assert_eq!(drops.get(), 1);
+        drop(world);
+        assert_eq!(drops.get(), 2);
+    }
+
+    #[test]
+    fn or_any_of_has() {
+        let world: World = World::new();
+        let e1 = world.spawn((A(1), B(true)));
+        let e2 = world.spawn(A(2));
+        let e3 = world.spawn((B(false), C(None)));
+        let e4 = world.spawn(C(Some("c")));
+        world.add_component(e4, Stunned(4)).unwrap();
+
+        let mut seen = vec![];
+        world.run(|e: &Entity, _: Or<(With<A>, With<Stunned>)>| seen.push(*e));
+        seen.sort();
+        assert_eq!(seen, [e1, e2, e4]);
+
+        let mut seen = vec![];
+        world.run(|e: &Entity, _: Or<(With<B>, Without<C>)>| seen.push(*e));
+        seen.sort();
+        assert_eq!(seen, [e1, e2, e3]);
+
+        let mut seen = vec![];
+        world.run(|e: &Entity, AnyOf((a, b)): AnyOf<(&mut A, &B)>| {
+            if let Some(a) = a {
+                a.0 += 10;
+            }
+            seen.push((*e, b.map(|b| b.0)));
+        });
+        seen.sort();
+        assert_eq!(seen, [(e1, Some(true)), (e2, None), (e3, Some(false))]);
+
+        let mut seen = vec![];
+        world.run(|e: &Entity, has_a: Has<A>, stunned: Has<Stunned>| {
+            seen.push((*e, *has_a, *stunned))
+        });
+        seen.sort();
+        assert_eq!(
+            seen,
+            [
+                (e1, true, false),
+                (e2, true, false),
+                (e3, false, false),
+                (e4, false, true)
+            ]
+        );
+
+        // Has does not borrow the component
+        let a = world.component_mut::<A>(e1).unwrap();
+        world.run(|_: Has<A>| {});
+        drop(a);
+
+        let query = world.query::<(&Entity, AnyOf<(&A, &C)>, Has<B>)>();
+        let mut items: Vec<_> = query
+            .iter()
+            .map(|(e, AnyOf((a, c)), has_b)| (*e, a.map(|a| a.0), c.is_some(), *has_b))
+            .collect();
+        items.sort();
+        assert_eq!(
+            items,
+            [
+                (e1, Some(11), false, true),
+                (e2, Some(12), false, false),
+                (e3, None, true, true),
+                (e4, None, true, false)
+            ]
+        );
+        drop(query);
+
+        assert_eq!(world.query::<Or<(With<B>, With<Stunned>)>>().count(), 3);
+        assert!(world
+            .query::<(AnyOf<(&A, &B)>, Without<A>, Without<B>)>()
+            .is_empty());
+        assert!(world.try_query::<AnyOf<(&mut A, &A)>>().is_err());
+    }
+
+    #[test]
+    fn chunked_iteration() {
+        fn changed(world: &World) -> usize {
+            let mut count = 0;
+            world.run(|_: Changed<G<f32>>| count += 1);
+            count
+        }
+
+        let world: World = World::new();
+        let mut entities = vec![];
+        for i in 0..100 {
+            entities.push(world.spawn((A(i), G(i as f32))));
+        }
+        for i in 100..150 {
+            world.spawn((A(i), G(i as f32), B(true)));
+        }
+        world.spawn(A(1000));
+        world.despawn(entities[10]).unwrap();
+        assert_eq!(changed(&world), 149);
+
+        let mut query = world.query::<(&Entity, &A, &mut G<f32>)>();
+        let mut lens = vec![];
+        for (entities, a, g) in query.chunks_mut() {
+            assert!(entities.len() == a.len() && a.len() == g.len());
+            for (g, a) in g.iter_mut().zip(a) {
+                g.0 += a.0 as f32;
+            }
+            lens.push(a.len());
+        }
+        lens.sort();
+        assert_eq!(lens, [50, 99]);
+        assert!(query.iter().all(|(_, a, g)| g.0 == 2.0 * a.0 as f32));
+        let ids: Vec<_> = query
+            .chunks()
+            .flat_map(|(entities, _, _)| entities)
+            .collect();
+        assert_eq!(ids.len(), 149);
+        assert!(!ids.contains(&&entities[10]));
+        drop(query);
+        assert_eq!(changed(&world), 149);
+
+        let query = world.query::<(Option<&B>, Has<G<f32>>, &A)>();
+        let mut chunks: Vec<_> = query
+            .chunks()
+            .map(|(b, has_g, a)| (b.map(|b| b.len()), has_g, a.len()))
+            .collect();
+        chunks.sort();
+        assert_eq!(
+            chunks,
+            [(None, false, 1), (None, true, 99), (Some(50), true, 50)]
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn chunks_need_dense_queries() {
+        let world: World = World::new();
+        world.spawn(A(0));
+        world.query::<(&A, Changed<A>)>().chunks().count();
+    }
+
+    #[test]
+    fn resources() {
+        struct Time(f32);
+        struct Score(u32);
+
+        let world: World = World::new();
+        assert!(world.insert_resource(Time(0.5)).is_none());
+        assert!(world.insert_resource(Score(0)).is_none());
+        assert!(world.has_resource::<Time>());
+        world.spawn(A(1));
+        world.spawn((A(2), B(true)));
+
+        world.run(|a: &A, time: Res<Time>, mut score: ResMut<Score>| {
+            score.0 += a.0 * (time.0 * 2.0) as u32
+        });
+        assert_eq!(world.resource::<Score>().unwrap().0, 3);
+
+        world.resource_mut::<Time>().unwrap().0 = 1.0;
+        {
+            let _time = world.resource::<Time>().unwrap();
+            assert!(world.try_run(|_: &A, _: Res<Time>| {}).is_ok());
+            assert!(matches!(
+                world.try_run(|_: &A, _: ResMut<Time>| {}),
+                Err(BorrowError::ResourceAlreadyBorrowed(_))
+            ));
+        }
+        assert!(world.try_run(|_: Res<Score>, _: ResMut<Score>| {}).is_err());
+
+        // Without components the system runs once per run, whatever the number of entities
+        world.resource_mut::<Score>().unwrap().0 = 3;
+        world.run(|time: Res<Time>, mut score: ResMut<Score>| score.0 += time.0 as u32);
+        assert_eq!(world.resource::<Score>().unwrap().0, 4);
+        let empty: World = World::new();
+        empty.insert_resource(Score(0));
+        empty.run(|mut score: ResMut<Score>| score.0 += 1);
+        assert_eq!(empty.resource::<Score>().unwrap().0, 1);
+
+        assert_eq!(world.insert_resource(Time(2.0)).map(|t| t.0), Some(1.0));
+        assert_eq!(world.remove_resource::<Score>().map(|s| s.0), Some(4));
+        assert!(world.resource::<Score>().is_none());
+        assert!(world.remove_resource::<Score>().is_none());
+    }
+
+    #[test]
+    #[should_panic]
+    fn insert_resource_while_running() {
+        struct Score;
+
+        let world: World = World::new();
+        world.spawn(A(1));
+        world.run(|_: &A| {
+            world.insert_resource(Score);
+        });
+    }
+
+    #[test]
+    #[should_panic]
+    fn missing_resource() {
+        let world: World = World::new();
+        world.spawn(A(1));
+        world.run(|_: &A, _: Res<u32>| {});
+    }
+
+    #[test]
+    fn schedule() {
+        use std::sync::Mutex;
+
+        let world: World = World::new();
+        world.spawn(A(1));
+        let log = Mutex::new(Vec::new());
+        let log = &log;
+
+        let mut schedule = Schedule::new();
+        schedule.add_stage("update").add_stage("late");
+        schedule
+            .add_system("update", move |_: &A| log.lock().unwrap().push("physics"))
+            .label("physics")
+            .after("input");
+        schedule
+            .add_system("update", move |_: &A| log.lock().unwrap().push("render"))
+            .after("physics");
+        schedule
+            .add_system("update", move |_: &A| log.lock().unwrap().push("input"))
+            .label("input");
+        schedule.add_system("update", |e: &Entity, a: &A, mut commands: Commands| {
+            if a.0 == 1 {
+                commands.add_component(*e, B(true));
+            }
+        });
+        schedule.add_system("late", move |_: &A, _: &B| log.lock().unwrap().push("late"));
+
+        schedule.run(&world);
+        assert_eq!(*log.lock().unwrap(), ["input", "physics", "render", "late"]);
+
+        let mut schedule = Schedule::new();
+        schedule.add_stage("update");
+        schedule
+            .add_system("update", |_: &A| {})
+            .label("a")
+            .after("c");
+        schedule
+            .add_system("update", |_: &A| {})
+            .label("b")
+            .after("a");
+        schedule
+            .add_system("update", |_: &A| {})
+            .label("c")
+            .after("b");
+        let err = schedule.try_run(&world).unwrap_err();
+        assert_eq!(
+            err,
+            ScheduleError::Cycle {
+                stage: "update",
+                systems: vec!["a", "b", "c", "a"],
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "systems a -> b -> c -> a form a cycle in stage update"
+        );
+
+        // c waits on the cycle but is not part of it
+        let mut schedule = Schedule::new();
+        schedule.add_stage("update");
+        schedule.add_system("update", |_: &A| {}).after("b");
+        schedule
+            .add_system("update", |_: &A| {})
+            .label("a")
+            .after("b");
+        schedule
+            .add_system("update", |_: &A| {})
+            .label("b")
+            .after("a");
+        assert_eq!(
+            schedule.build(),
+            Err(ScheduleError::Cycle {
+                stage: "update",
+                systems: vec!["b", "a", "b"],
+            })
+        );
+
+        let mut schedule = Schedule::new();
+        schedule.add_stage("update");
+        schedule.add_system("update", |_: &A| {}).before("missing");
+        assert_eq!(
+            schedule.build(),
+            Err(ScheduleError::UnknownLabel("missing"))
+        );
+
+        let mut schedule = Schedule::new();
+        schedule.add_stage("update").add_stage("late");
+        schedule.add_system("late", |_: &A| {}).label("late");
+        schedule.add_system("update", |_: &A| {}).before("late");
+        assert_eq!(
+            schedule.build(),
+            Err(ScheduleError::LabelInOtherStage {
+                label: "late",
+                stage: "update",
+            })
+        );
+    }
+
+    #[test]
+    fn parallel_schedule() {
+        use std::collections::HashSet;
+        use std::sync::atomic::{AtomicU32, Ordering};
+        use std::sync::{Condvar, Mutex};
+        use std::thread;
+        use std::time::Duration;
+
+        fn build(schedule: &mut Schedule<'_>) {
+            schedule.add_stage("update");
+            schedule.add_system("update", |a: &mut A| a.0 += 1);
+            schedule.add_system("update", |a: &A, b: &mut B| b.0 = a.0.is_multiple_of(2));
+            schedule.add_system("update", |_: &B, mut commands: Commands| {
+                commands.spawn(A(100));
+            });
+            schedule.add_system("update", |a: &mut A, _: &B| a.0 *= 3);
+            schedule.add_system("update", |_: &A, mut commands: Commands| {
+                commands.spawn(B(false));
             });
-            assert_eq!(10, acc);
         }
+
+        fn contents(world: &World) -> Vec<(Entity, Option<u32>, Option<bool>)> {
+            let query = world.query::<(&Entity, Option<&A>, Option<&B>)>();
+            let mut items: Vec<_> = query
+                .iter()
+                .map(|(e, a, b)| (*e, a.map(|a| a.0), b.map(|b| b.0)))
+                .collect();
+            items.sort_by_key(|(e, ..)| **e);
+            items
+        }
+
+        let serial: World = World::new();
+        let parallel: World = World::new();
+        for world in [&serial, &parallel] {
+            for i in 0..10 {
+                world.spawn((A(i), B(false)));
+            }
+        }
+
+        let mut serial_schedule = Schedule::new();
+        build(&mut serial_schedule);
+        let mut parallel_schedule = Schedule::new();
+        build(&mut parallel_schedule);
+        parallel_schedule.set_num_threads(4);
+        for _ in 0..3 {
+            serial_schedule.run(&serial);
+            parallel_schedule.run(&parallel);
+        }
+        assert_eq!(contents(&serial), contents(&parallel));
+
+        // Only passes if the systems run at the same time, each waits a while for the other one
+        let world: World = World::new();
+        world.spawn((A(0), B(false)));
+        let arrived = (Mutex::new(0), Condvar::new());
+        let met = AtomicU32::new(0);
+        let meet = || {
+            let (count, changed) = &arrived;
+            let mut count = count.lock().unwrap();
+            *count += 1;
+            changed.notify_all();
+            let (count, _) = changed
+                .wait_timeout_while(count, Duration::from_secs(10), |count| *count < 2)
+                .unwrap();
+            if *count == 2 {
+                met.fetch_add(1, Ordering::Relaxed);
+            }
+        };
+        let meet = &meet;
+        let mut schedule = Schedule::new();
+        schedule.add_stage("update").set_num_threads(2);
+        schedule.add_system("update", move |_: &mut A| meet());
+        schedule.add_system("update", move |_: &mut B| meet());
+        schedule.run(&world);
+        assert_eq!(met.load(Ordering::Relaxed), 2);
+
+        // The threads are kept between runs, and the systems with commands stay on this one
+        let caller = thread::current().id();
+        let threads = Mutex::new(HashSet::new());
+        let threads = &threads;
+        let mut schedule = Schedule::new();
+        schedule.add_stage("update").set_num_threads(4);
+        for _ in 0..4 {
+            schedule.add_system("update", move |_: &A| {
+                threads.lock().unwrap().insert(thread::current().id());
+            });
+        }
+        schedule.add_system("update", move |_: &A, _: Commands| {
+            assert_eq!(thread::current().id(), caller);
+        });
+        for _ in 0..20 {
+            schedule.run(&world);
+        }
+        assert!(threads.lock().unwrap().len() <= 4);
+    }
+
+    #[test]
+    fn par_for_each() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+
+        let world: World = World::new();
+        for i in 0..5000 {
+            world.spawn(A(i));
+        }
+        for i in 0..500 {
+            world.spawn((A(i), B(false)));
+        }
+
+        {
+            let mut query = world.query::<(&Entity, &mut A)>();
+            query
+                .set_batch_size(64)
+                .par_for_each_mut(|(e, a), commands| {
+                    a.0 += 1;
+                    if a.0 % 1000 == 0 {
+                        commands.add_component(*e, C(None));
+                    }
+                });
+            assert!(world.query::<&C>().is_empty());
+        }
+        assert_eq!(world.query::<&C>().count(), 5);
+
+        let sum = AtomicU32::new(0);
+        world.query::<(&A, Without<C>)>().par_for_each(|(a, _), _| {
+            sum.fetch_add(a.0, Ordering::Relaxed);
+        });
+        let expected: u32 = (1..=5000).chain(1..=500).filter(|i| i % 1000 != 0).sum();
+        assert_eq!(sum.into_inner(), expected);
+
+        let world: World = World::new();
+        for i in 0..100 {
+            world.spawn(A(i));
+        }
+        let mut query = world.query::<&A>();
+        query.set_batch_size(7).par_for_each(|a, commands| {
+            commands.spawn(B(a.0 % 2 == 0));
+        });
+        drop(query);
+        let spawned: Vec<_> = world.query::<&B>().iter().map(|b| b.0).collect();
+        assert_eq!(spawned, (0..100).map(|i| i % 2 == 0).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn run_conditions() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+
+        struct Paused(bool);
+
+        let world: World = World::new();
+        world.insert_resource(Paused(false));
+        let e = world.spawn(A(0));
+        let moved = AtomicU32::new(0);
+        let every_other = AtomicU32::new(0);
+        let seen_changes = AtomicU32::new(0);
+        let (moved, every_other, seen_changes) = (&moved, &every_other, &seen_changes);
+
+        let mut schedule = Schedule::new();
+        schedule.add_stage("update");
+        schedule
+            .add_system("update", move |_: &A| {
+                moved.fetch_add(1, Ordering::Relaxed);
+            })
+            .label("movement")
+            .run_if(|world| !world.resource::<Paused>().unwrap().0);
+        let mut frame = 0;
+        schedule
+            .add_system("update", move |_: &A| {
+                every_other.fetch_add(1, Ordering::Relaxed);
+            })
+            .label("movement")
+            .run_if(move |_| {
+                frame += 1;
+                frame % 2 == 0
+            });
+        schedule
+            .add_system("update", move |_: &A, _: Changed<A>| {
+                seen_changes.fetch_add(1, Ordering::Relaxed);
+            })
+            .label("observer");
+
+        for _ in 0..4 {
+            schedule.run(&world);
+        }
+        assert_eq!(moved.load(Ordering::Relaxed), 4);
+        assert_eq!(every_other.load(Ordering::Relaxed), 2);
+        assert_eq!(seen_changes.load(Ordering::Relaxed), 1);
+
+        world.resource_mut::<Paused>().unwrap().0 = true;
+        schedule.run(&world);
+        schedule.run(&world);
+        assert_eq!(moved.load(Ordering::Relaxed), 4);
+        assert_eq!(every_other.load(Ordering::Relaxed), 3);
+
+        schedule.disable("movement").disable("observer");
+        assert!(!schedule.is_enabled("movement"));
+        world.component_mut::<A>(e).unwrap().0 = 1;
+        schedule.run(&world);
+        schedule.run(&world);
+        assert_eq!(every_other.load(Ordering::Relaxed), 3);
+        assert_eq!(seen_changes.load(Ordering::Relaxed), 1);
+
+        // The observer missed nothing while it was disabled
+        schedule.enable("observer");
+        schedule.run(&world);
+        assert_eq!(seen_changes.load(Ordering::Relaxed), 2);
+
+        // Runs when the resource changed, through `ResMut` or `resource_mut`
+        struct Score(u32);
+        world.insert_resource(Score(0));
+        let rebuilt = AtomicU32::new(0);
+        let rebuilt = &rebuilt;
+        let mut schedule = Schedule::new();
+        schedule.add_stage("input").add_stage("update");
+        let mut frame = 0;
+        schedule
+            .add_system("input", |mut score: ResMut<Score>| score.0 += 1)
+            .run_if(move |_| {
+                frame += 1;
+                frame == 3
+            });
+        schedule
+            .add_system("update", move |_: Res<Score>| {
+                rebuilt.fetch_add(1, Ordering::Relaxed);
+            })
+            .run_if(resource_changed::<Score>());
+        let mut counts = vec![];
+        for frame in 0..6 {
+            if frame == 4 {
+                drop(world.resource_mut::<Score>().unwrap());
+            }
+            schedule.run(&world);
+            counts.push(rebuilt.load(Ordering::Relaxed));
+        }
+        assert_eq!(counts, [1, 1, 2, 2, 3, 3]);
+        assert_eq!(world.resource::<Score>().unwrap().0, 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn disable_unknown_label() {
+        Schedule::new().disable("missing");
+    }
+
+    #[test]
+    fn insert_with_stale_handle() {
+        use std::sync::{Arc, Mutex};
+
+        let world: World = World::new();
+        let old = world.spawn(A(1));
+        world.despawn(old).unwrap();
+        let new = world.spawn(A(2));
+        assert_eq!(*old, *new);
+
+        assert_eq!(
+            world.try_insert(old, A(99)),
+            Err(EcsError::StaleEntity(old))
+        );
+        assert!(world.is_alive(new));
+        assert!(!world.is_alive(old));
+        assert_eq!(world.component::<A>(new).unwrap().0, 2);
+
+        let errors = Arc::new(Mutex::new(vec![]));
+        let errors_ = errors.clone();
+        world.on_command_error(move |err| errors_.lock().unwrap().push(err));
+        world.commands().insert(old, A(99));
+        world.flush();
+        assert_eq!(*errors.lock().unwrap(), [EcsError::StaleEntity(old)]);
+        assert_eq!(world.component::<A>(new).unwrap().0, 2);
     }
 }